@@ -0,0 +1,72 @@
+//! Counts heap allocations made while lexing, with tokens borrowing their
+//! text from the input (after) versus owning a copy of it (before), as they
+//! did before tokens borrowed. Only the text is copied, once for every token
+//! and child token, so the difference is what the owned text cost.
+//!
+//! Run it as `cargo run --release --example alloc_bench`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pulp::lexer::builder::re;
+use pulp::lexer::instruction::Token;
+use pulp::{Lexer, Select};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn count<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let value = f();
+    (value, ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+/// Copies the text of `token` and of every token under it.
+fn copy_text(token: &Token, copies: &mut Vec<String>) {
+    copies.push(token.text.to_string());
+    for child in &token.children {
+        copy_text(child, copies);
+    }
+}
+
+fn main() {
+    let lexer = Lexer::tokenizer(
+        vec![
+            re(r"\w+").save("ident"),
+            re(r"\d+").save("number"),
+            re(r"\s+"),
+        ],
+        Select::First,
+    )
+    .unwrap();
+    let input = "let answer 42\n".repeat(2_000);
+
+    let (tokens, borrowed) = count(|| lexer.lex(&input).unwrap());
+    let mut texts = Vec::with_capacity(tokens.len());
+    let ((), copies) = count(|| tokens.iter().for_each(|t| copy_text(t, &mut texts)));
+
+    let before = borrowed + copies;
+    println!("tokens:                     {}", tokens.len());
+    println!("allocations, owned text:    {}", before);
+    println!("allocations, borrowed text: {}", borrowed);
+    println!(
+        "saved:                      {} ({:.1} per token)",
+        copies,
+        copies as f64 / tokens.len() as f64
+    );
+}
@@ -0,0 +1,476 @@
+//! Static checks of a grammar, run when a lexer is built and again by every
+//! builder adding statements to it. Loops whose body can match empty input
+//! would never end and are rejected. Alternatives and rules that can never
+//! match are reported as warnings.
+
+use std::collections::HashMap;
+
+use super::instruction::{Fixity, Statement};
+use super::{Mode, Select};
+use crate::regex::instruction::Instruction as Class;
+
+/// The statements a lexer is built from, kept to check them again whenever
+/// they change.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Grammar {
+    /// The rules of every mode, the default one first. A lexer in
+    /// `Mode::Structure` has its grammar as the only rule.
+    pub modes: Vec<(String, Vec<Statement>)>,
+    pub trivia: Option<Statement>,
+    /// The rules `Recovery::Sync` skips to.
+    pub sync: Vec<Statement>,
+    /// The named rules `Statement::Rule` refers to.
+    pub rules: Vec<(String, Statement)>,
+}
+
+/// What the analysis knows about a statement.
+#[derive(Debug, PartialEq, Clone)]
+struct Facts {
+    /// It can match empty input.
+    nullable: bool,
+    /// It matches at every position.
+    infallible: bool,
+    /// The classes of characters a non empty match can start with, `None`
+    /// when unknown.
+    first: Option<Vec<Class>>,
+    /// It matches wherever the next character is in `first`.
+    decided: bool,
+}
+
+impl Facts {
+    /// Facts of a statement that never matches, such as a rule that is not
+    /// defined.
+    fn never() -> Facts {
+        Facts {
+            nullable: false,
+            infallible: false,
+            first: Some(vec![]),
+            decided: false,
+        }
+    }
+
+    fn empty(infallible: bool) -> Facts {
+        Facts {
+            nullable: true,
+            infallible,
+            first: Some(vec![]),
+            decided: false,
+        }
+    }
+
+    /// Facts of a statement matching any one of `alternatives`.
+    fn any(alternatives: &[Facts]) -> Facts {
+        Facts {
+            nullable: alternatives.iter().any(|f| f.nullable),
+            infallible: alternatives.iter().any(|f| f.infallible),
+            first: union(alternatives.iter().map(|f| &f.first)),
+            decided: !alternatives.is_empty()
+                && alternatives.iter().all(|f| f.decided || f.infallible),
+        }
+    }
+
+    /// Facts of `items` matched one after the other.
+    fn all(items: &[Facts]) -> Facts {
+        let reached = items
+            .iter()
+            .position(|f| !f.nullable)
+            .map_or(items.len(), |i| i + 1);
+        Facts {
+            nullable: items.iter().all(|f| f.nullable),
+            infallible: items.iter().all(|f| f.infallible),
+            first: union(items[..reached].iter().map(|f| &f.first)),
+            decided: items.first().is_some_and(|f| f.decided && !f.nullable)
+                && items[1..].iter().all(|f| f.infallible),
+        }
+    }
+}
+
+fn union<'a>(sets: impl Iterator<Item = &'a Option<Vec<Class>>>) -> Option<Vec<Class>> {
+    let mut all = vec![];
+    for set in sets {
+        for class in set.as_ref()? {
+            if !all.contains(class) {
+                all.push(class.clone());
+            }
+        }
+    }
+    Some(all)
+}
+
+/// Whether every character of `inner` is in `outer`.
+fn covers(outer: &[Class], inner: &[Class]) -> bool {
+    inner.iter().all(|class| {
+        outer.iter().any(|o| {
+            o == class
+                || match (o, class) {
+                    (Class::Any, _) => true,
+                    (Class::AnyWhitespace, Class::Char(c)) => c.is_whitespace(),
+                    (Class::AnyNonWhitespace, Class::Char(c)) => !c.is_whitespace(),
+                    (Class::AnyDigit, Class::Char(c)) => c.is_numeric(),
+                    (Class::AnyNonDigit, Class::Char(c)) => !c.is_numeric(),
+                    (Class::AnyWord, Class::Char(c)) => c.is_alphabetic(),
+                    (Class::AnyNonWord, Class::Char(c)) => !c.is_alphabetic(),
+                    (Class::AnyNonWord | Class::AnyNonDigit, Class::AnyWhitespace) => true,
+                    (Class::AnyNonWhitespace, Class::AnyDigit | Class::AnyWord) => true,
+                    _ => false,
+                }
+        })
+    })
+}
+
+/// What analysing a statement needs and finds besides its facts.
+#[derive(Default)]
+struct Env {
+    /// The facts of the named rules.
+    rules: HashMap<String, Facts>,
+    warnings: Vec<String>,
+}
+
+/// Checks every statement of a lexer in `mode`, returning the warnings. The
+/// grammar of `Mode::Structure` is reported without a prefix, the rules of
+/// the default mode by position, counting from 1, and the other statements
+/// with what they are.
+pub fn check(grammar: &Grammar, mode: Mode) -> Result<Vec<String>, String> {
+    let mut env = Env {
+        rules: rule_facts(&grammar.rules)?,
+        warnings: vec![],
+    };
+    let mut warnings = vec![];
+    let select = match mode {
+        Mode::Tokens(select) => select,
+        Mode::Structure => Select::First,
+    };
+    for (i, (name, statements)) in grammar.modes.iter().enumerate() {
+        match (mode, i) {
+            (Mode::Structure, 0) => {
+                for statement in statements {
+                    check_grammar(statement, &mut env, &mut warnings)?;
+                }
+            }
+            (_, 0) => check_rules(
+                statements,
+                select,
+                &|i| format!("Rule {}", i),
+                &mut env,
+                &mut warnings,
+            )?,
+            _ => check_rules(
+                statements,
+                select,
+                &|i| format!("Rule {} of mode {}", i, name),
+                &mut env,
+                &mut warnings,
+            )?,
+        }
+    }
+    if let Some(trivia) = &grammar.trivia {
+        check_one(trivia, "Trivia", &mut env, &mut warnings)?;
+    }
+    for (i, rule) in grammar.sync.iter().enumerate() {
+        check_one(
+            rule,
+            &format!("Sync rule {}", i + 1),
+            &mut env,
+            &mut warnings,
+        )?;
+    }
+    for (name, rule) in &grammar.rules {
+        check_one(rule, &format!("Rule {:?}", name), &mut env, &mut warnings)?;
+    }
+    Ok(warnings)
+}
+
+/// The facts of every named rule. Rules may refer to each other, so they are
+/// analysed again and again, starting from matching nothing, until their
+/// facts stop growing. Facts only grow, so an error found on the way stands.
+fn rule_facts(rules: &[(String, Statement)]) -> Result<HashMap<String, Facts>, String> {
+    let mut env = Env::default();
+    for (name, _) in rules {
+        env.rules.insert(name.clone(), Facts::never());
+    }
+    loop {
+        let mut grown = HashMap::new();
+        for (name, rule) in rules {
+            let facts = analyse(rule, &mut env).map_err(|e| format!("Rule {:?}: {}", name, e))?;
+            grown.insert(name.clone(), facts);
+        }
+        env.warnings.clear();
+        if grown == env.rules {
+            return Ok(grown);
+        }
+        env.rules = grown;
+    }
+}
+
+/// Checks the grammar of a lexer in `Mode::Structure`.
+fn check_grammar(
+    grammar: &Statement,
+    env: &mut Env,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let facts = analyse(grammar, env)?;
+    warnings.append(&mut env.warnings);
+    if never_matches(&facts) {
+        warnings.push("The grammar can never match".to_string());
+    }
+    Ok(())
+}
+
+/// Checks a statement on its own, prefixing what it finds with `label`.
+fn check_one(
+    statement: &Statement,
+    label: &str,
+    env: &mut Env,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let facts = analyse(statement, env).map_err(|e| format!("{}: {}", label, e))?;
+    warnings.extend(env.warnings.drain(..).map(|w| format!("{}: {}", label, w)));
+    if never_matches(&facts) {
+        warnings.push(format!("{} can never match", label));
+    }
+    Ok(())
+}
+
+/// Checks the rules of a mode, prefixing the warnings of every rule with its
+/// `label`, given its position counting from 1. Rules that match no more than
+/// empty input are never used either.
+fn check_rules(
+    rules: &[Statement],
+    select: Select,
+    label: &dyn Fn(usize) -> String,
+    env: &mut Env,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let mut facts: Vec<Facts> = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        let rule_facts = analyse(rule, env).map_err(|e| format!("{}: {}", label(i + 1), e))?;
+        warnings.extend(
+            env.warnings
+                .drain(..)
+                .map(|w| format!("{}: {}", label(i + 1), w)),
+        );
+        if never_matches(&rule_facts) {
+            warnings.push(format!("{} can never match", label(i + 1)));
+        } else if let Some(j) = rules[..i].iter().position(|r| r == rule) {
+            warnings.push(format!(
+                "{} is never used, rule {} is the same",
+                label(i + 1),
+                j + 1
+            ));
+        } else if select == Select::First {
+            if let Some(j) = facts.iter().position(|f| shadows(f, &rule_facts)) {
+                warnings.push(format!(
+                    "{} is never used, rule {} matches wherever it could",
+                    label(i + 1),
+                    j + 1
+                ));
+            }
+        }
+        facts.push(rule_facts);
+    }
+    Ok(())
+}
+
+/// Whether a statement matches nothing, not even empty input.
+fn never_matches(facts: &Facts) -> bool {
+    !facts.nullable && facts.first.as_ref().is_some_and(|first| first.is_empty())
+}
+
+/// Whether `earlier` always matches some input where `later` could match
+/// any, so that an ordered choice never gets to try `later`.
+fn shadows(earlier: &Facts, later: &Facts) -> bool {
+    match (&earlier.first, &later.first) {
+        (Some(outer), Some(inner)) => {
+            earlier.decided && !later.nullable && !inner.is_empty() && covers(outer, inner)
+        }
+        _ => false,
+    }
+}
+
+fn analyse_all(statements: &[Statement], env: &mut Env) -> Result<Vec<Facts>, String> {
+    statements.iter().map(|s| analyse(s, env)).collect()
+}
+
+fn analyse(statement: &Statement, env: &mut Env) -> Result<Facts, String> {
+    let facts = match statement {
+        Statement::Reader(regex) => {
+            let (first, decided) = regex.first();
+            Facts {
+                nullable: regex.nullable(),
+                infallible: regex.nullable(),
+                first: Some(first),
+                decided,
+            }
+        }
+        Statement::Keywords(words) => {
+            let empty = words.iter().any(|w| w.is_empty());
+            let mut first = vec![];
+            for c in words.iter().filter_map(|w| w.chars().next()) {
+                if !first.contains(&Class::Char(c)) {
+                    first.push(Class::Char(c));
+                }
+            }
+            Facts {
+                nullable: empty,
+                infallible: empty,
+                first: Some(first),
+                decided: words.iter().all(|w| w.chars().count() == 1),
+            }
+        }
+        Statement::Concat(items) => Facts::all(&analyse_all(items, env)?),
+        Statement::Alternation(lhs, rhs) => Facts::any(&[analyse(lhs, env)?, analyse(rhs, env)?]),
+        Statement::Choice(alternatives) | Statement::Longest(alternatives) => {
+            Facts::any(&analyse_all(alternatives, env)?)
+        }
+        Statement::Ordered(alternatives) => {
+            let facts = analyse_all(alternatives, env)?;
+            for (j, later) in facts.iter().enumerate() {
+                let unreachable = facts[..j].iter().enumerate().find_map(|(i, earlier)| {
+                    if earlier.infallible {
+                        Some(format!("alternative {} always matches", i + 1))
+                    } else if alternatives[i] == alternatives[j] {
+                        Some(format!("alternative {} is the same", i + 1))
+                    } else if shadows(earlier, later) {
+                        Some(format!("alternative {} matches wherever it could", i + 1))
+                    } else {
+                        None
+                    }
+                });
+                if let Some(reason) = unreachable {
+                    env.warnings.push(format!(
+                        "Alternative {} of an ordered choice is never tried, {}",
+                        j + 1,
+                        reason
+                    ));
+                }
+            }
+            Facts::any(&facts)
+        }
+        Statement::ZeroOrOne(inner) => Facts {
+            first: analyse(inner, env)?.first,
+            ..Facts::empty(true)
+        },
+        Statement::ZeroOrMore(inner) => Facts {
+            first: looped(inner, "ZeroOrMore", env)?.first,
+            ..Facts::empty(true)
+        },
+        Statement::OneOrMore(inner) => looped(inner, "OneOrMore", env)?,
+        Statement::Repeat { inner, min, max } => {
+            if let Some(max) = max.filter(|max| max < min) {
+                return Err(format!(
+                    "Repeat needs at least {} items but allows at most {}",
+                    min, max
+                ));
+            }
+            let inner = match max {
+                Some(_) => analyse(inner, env)?,
+                None => looped(inner, "Repeat", env)?,
+            };
+            Facts {
+                nullable: *min == 0 || inner.nullable,
+                infallible: *min == 0 || inner.infallible,
+                decided: *min == 1 && inner.decided,
+                first: inner.first,
+            }
+        }
+        Statement::Condition {
+            cond,
+            then,
+            otherwise,
+        } => {
+            analyse(cond, env)?;
+            let then = analyse(then, env)?;
+            let otherwise = match otherwise {
+                Some(otherwise) => analyse(otherwise, env)?,
+                None => Facts::empty(true),
+            };
+            Facts {
+                decided: false,
+                infallible: then.infallible && otherwise.infallible,
+                ..Facts::any(&[then, otherwise])
+            }
+        }
+        Statement::Not(cond) | Statement::And(cond) => {
+            analyse(cond, env)?;
+            Facts::empty(false)
+        }
+        Statement::Save(_, inner) | Statement::SaveWith(_, _, inner) => analyse(inner, env)?,
+        Statement::SepBy {
+            item,
+            sep,
+            trailing: _,
+            min,
+        } => {
+            let item = analyse(item, env)?;
+            let sep = analyse(sep, env)?;
+            if item.nullable && sep.nullable {
+                return Err(loop_error("SepBy"));
+            }
+            Facts {
+                nullable: *min == 0 || item.nullable && (*min == 1 || sep.nullable),
+                infallible: *min == 0 || item.infallible && (*min == 1 || sep.infallible),
+                decided: *min == 1 && item.decided,
+                first: item.first,
+            }
+        }
+        Statement::Delimited { open, inner, close } => Facts {
+            infallible: false,
+            decided: false,
+            ..Facts::all(&[
+                analyse(open, env)?,
+                analyse(inner, env)?,
+                analyse(close, env)?,
+            ])
+        },
+        Statement::PushMode(_) | Statement::PopMode => Facts::empty(true),
+        Statement::Rule(name) => Facts {
+            decided: false,
+            ..env.rules.get(name).cloned().unwrap_or_else(Facts::never)
+        },
+        Statement::Precedence { atom, operators } => {
+            let atom = analyse(atom, env)?;
+            let mut prefixes = vec![];
+            for operator in operators {
+                let op = analyse(&operator.op, env)?;
+                // the operator would apply again and again without reading
+                // anything
+                let spins = match operator.fixity {
+                    Fixity::Prefix | Fixity::Postfix => op.nullable,
+                    Fixity::InfixLeft | Fixity::InfixRight => op.nullable && atom.nullable,
+                };
+                if spins {
+                    return Err(format!(
+                        "Operator {} can match empty input and would apply forever",
+                        operator.name
+                    ));
+                }
+                if operator.fixity == Fixity::Prefix {
+                    prefixes.push(op);
+                }
+            }
+            let first = union(prefixes.iter().chain([&atom]).map(|f| &f.first));
+            Facts {
+                first,
+                decided: false,
+                ..atom
+            }
+        }
+    };
+    Ok(facts)
+}
+
+/// Analyses the body of a loop, which must read input on every round.
+fn looped(inner: &Statement, kind: &str, env: &mut Env) -> Result<Facts, String> {
+    let facts = analyse(inner, env)?;
+    match facts.nullable {
+        true => Err(loop_error(kind)),
+        false => Ok(facts),
+    }
+}
+
+fn loop_error(kind: &str) -> String {
+    format!(
+        "{} loops over a statement that can match empty input and would never end",
+        kind
+    )
+}
@@ -0,0 +1,269 @@
+use std::ops::{Add, BitOr};
+
+use super::instruction::{OpDef, Statement};
+use super::value::Transform;
+use crate::regex::Regex;
+
+/// Builds a `Statement::Reader` from a regex pattern.
+///
+/// # Panics
+///
+/// Panics if `pattern` is not a valid regex. Grammars are usually written as
+/// literals, so an invalid pattern is a programming error.
+pub fn re(pattern: &str) -> Statement {
+    match Regex::new(pattern) {
+        Ok(regex) => Statement::Reader(regex),
+        Err(e) => panic!("invalid pattern {:?}: {}", pattern, e),
+    }
+}
+
+/// Builds a `Statement::Concat` from a list of statements.
+pub fn seq(items: Vec<Statement>) -> Statement {
+    Statement::Concat(items)
+}
+
+/// Builds a `Statement::PushMode` switching the lexer to the mode `name`.
+pub fn push_mode(name: &str) -> Statement {
+    Statement::PushMode(name.to_string())
+}
+
+/// Builds a `Statement::PopMode` returning the lexer to the previous mode.
+pub fn pop_mode() -> Statement {
+    Statement::PopMode
+}
+
+/// Builds a `Statement::Rule` referring to the lexer rule `name`.
+pub fn rule(name: &str) -> Statement {
+    Statement::Rule(name.to_string())
+}
+
+/// Builds a `Statement::Precedence` combining `atom`s with `operators`.
+pub fn precedence(atom: Statement, operators: Vec<OpDef>) -> Statement {
+    Statement::Precedence {
+        atom: Box::new(atom),
+        operators,
+    }
+}
+
+/// Builds a `Statement::Choice` over `alternatives`.
+pub fn choice(alternatives: Vec<Statement>) -> Statement {
+    Statement::Choice(alternatives)
+}
+
+/// Builds a `Statement::Keywords` matching the longest of `words`.
+pub fn keywords(words: &[&str]) -> Statement {
+    Statement::Keywords(words.iter().map(|w| w.to_string()).collect())
+}
+
+/// Builds a `Statement::Ordered` PEG choice over `alternatives`.
+pub fn ordered(alternatives: Vec<Statement>) -> Statement {
+    Statement::Ordered(alternatives)
+}
+
+/// Builds a `Statement::Longest` keeping the longest match among
+/// `alternatives`.
+pub fn longest(alternatives: Vec<Statement>) -> Statement {
+    Statement::Longest(alternatives)
+}
+
+/// Builds a `Statement::SepBy` matching at least `min` `item`s separated by
+/// `sep`, with an optional trailing `sep` when `trailing` is set.
+pub fn sep_by(item: Statement, sep: Statement, trailing: bool, min: usize) -> Statement {
+    Statement::SepBy {
+        item: Box::new(item),
+        sep: Box::new(sep),
+        trailing,
+        min,
+    }
+}
+
+/// Builds a `Statement::Delimited` matching `inner` between `open` and
+/// `close`.
+pub fn delimited(open: Statement, inner: Statement, close: Statement) -> Statement {
+    Statement::Delimited {
+        open: Box::new(open),
+        inner: Box::new(inner),
+        close: Box::new(close),
+    }
+}
+
+/// Builds a `Statement::Not` that succeeds where `cond` does not match.
+pub fn not(cond: Statement) -> Statement {
+    Statement::Not(Box::new(cond))
+}
+
+/// Builds a `Statement::And` that succeeds where `cond` matches, without
+/// consuming it.
+pub fn and(cond: Statement) -> Statement {
+    Statement::And(Box::new(cond))
+}
+
+/// Builds a `Statement::Condition` running `then` where `cond` matches and
+/// `otherwise` where it does not.
+pub fn if_else(cond: Statement, then: Statement, otherwise: Statement) -> Statement {
+    Statement::Condition {
+        cond: Box::new(cond),
+        then: Box::new(then),
+        otherwise: Some(Box::new(otherwise)),
+    }
+}
+
+impl Statement {
+    /// Matches `self` followed by `next`.
+    pub fn then(self, next: Statement) -> Statement {
+        match self {
+            Statement::Concat(mut items) => {
+                items.push(next);
+                Statement::Concat(items)
+            }
+            first => Statement::Concat(vec![first, next]),
+        }
+    }
+
+    /// Matches `self`, or `other` when `self` fails.
+    pub fn or(self, other: Statement) -> Statement {
+        Statement::Alternation(Box::new(self), Box::new(other))
+    }
+
+    /// Matches `self` zero or one time.
+    pub fn opt(self) -> Statement {
+        Statement::ZeroOrOne(Box::new(self))
+    }
+
+    /// Matches `self` zero or more times.
+    pub fn many(self) -> Statement {
+        Statement::ZeroOrMore(Box::new(self))
+    }
+
+    /// Matches `self` one or more times.
+    pub fn many1(self) -> Statement {
+        Statement::OneOrMore(Box::new(self))
+    }
+
+    /// Matches `self` between `min` and `max` times, or at least `min` times
+    /// when `max` is `None`.
+    pub fn repeat(self, min: usize, max: Option<usize>) -> Statement {
+        Statement::Repeat {
+            inner: Box::new(self),
+            min,
+            max,
+        }
+    }
+
+    /// Matches `self` only when `cond` matches at the same position.
+    pub fn when(self, cond: Statement) -> Statement {
+        Statement::Condition {
+            cond: Box::new(cond),
+            then: Box::new(self),
+            otherwise: None,
+        }
+    }
+
+    /// Saves whatever `self` matched under `name`.
+    pub fn save(self, name: &str) -> Statement {
+        Statement::Save(name.to_string(), Box::new(self))
+    }
+
+    /// Saves whatever `self` matched under `name`, converting its text with
+    /// `transforms` into the token's `value`.
+    pub fn save_with(self, name: &str, transforms: &[Transform]) -> Statement {
+        Statement::SaveWith(name.to_string(), transforms.to_vec(), Box::new(self))
+    }
+}
+
+impl Add for Statement {
+    type Output = Statement;
+
+    fn add(self, rhs: Statement) -> Statement {
+        self.then(rhs)
+    }
+}
+
+impl BitOr for Statement {
+    type Output = Statement;
+
+    fn bitor(self, rhs: Statement) -> Statement {
+        self.or(rhs)
+    }
+}
+
+/// Builds a `Statement` from a small declarative syntax.
+///
+/// - `"pattern"` is a regex reader,
+/// - `( ... )` groups,
+/// - `name: item` saves `item` under `name`,
+/// - `{ expr }` embeds any expression evaluating to a `Statement`,
+/// - `?`, `*` and `+` after an item repeat it,
+/// - `|` separates alternatives.
+///
+/// ```
+/// use pulp::grammar;
+///
+/// let ident = grammar!(ident: ("@" "\\w+") "\\s*"?);
+/// ```
+#[macro_export]
+macro_rules! grammar {
+    (@alt [$($alt:expr),*] [$($seq:expr),*]) => {
+        $crate::lexer::builder::__alternatives(vec![$($alt,)* $crate::lexer::builder::__sequence(vec![$($seq),*])])
+    };
+    (@alt [$($alt:expr),*] [$($seq:expr),*] | $($rest:tt)*) => {
+        $crate::grammar!(@alt [$($alt,)* $crate::lexer::builder::__sequence(vec![$($seq),*])] [] $($rest)*)
+    };
+    (@alt $alt:tt [$($seq:expr),*] { $item:expr } ? $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt [$($seq,)* ($item).opt()] $($rest)*)
+    };
+    (@alt $alt:tt [$($seq:expr),*] { $item:expr } * $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt [$($seq,)* ($item).many()] $($rest)*)
+    };
+    (@alt $alt:tt [$($seq:expr),*] { $item:expr } + $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt [$($seq,)* ($item).many1()] $($rest)*)
+    };
+    (@alt $alt:tt [$($seq:expr),*] { $item:expr } $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt [$($seq,)* $item] $($rest)*)
+    };
+    (@alt $alt:tt $seq:tt $name:ident : $pattern:literal $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt $seq { $crate::lexer::builder::re($pattern).save(stringify!($name)) } $($rest)*)
+    };
+    (@alt $alt:tt $seq:tt $name:ident : ( $($group:tt)* ) $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt $seq { $crate::grammar!($($group)*).save(stringify!($name)) } $($rest)*)
+    };
+    (@alt $alt:tt $seq:tt $name:ident : { $item:expr } $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt $seq { ($item).save(stringify!($name)) } $($rest)*)
+    };
+    (@alt $alt:tt $seq:tt $pattern:literal $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt $seq { $crate::lexer::builder::re($pattern) } $($rest)*)
+    };
+    (@alt $alt:tt $seq:tt ( $($group:tt)* ) $($rest:tt)*) => {
+        $crate::grammar!(@alt $alt $seq { $crate::grammar!($($group)*) } $($rest)*)
+    };
+    ($($body:tt)*) => {
+        $crate::grammar!(@alt [] [] $($body)*)
+    };
+}
+
+/// Builds a `Lexer` from the `grammar!` syntax.
+///
+/// ```
+/// use pulp::lexer;
+///
+/// let lexer = lexer!(ident: "\\w+").unwrap();
+/// ```
+#[macro_export]
+macro_rules! lexer {
+    ($($body:tt)*) => {
+        $crate::Lexer::new($crate::grammar!($($body)*))
+    };
+}
+
+#[doc(hidden)]
+pub fn __sequence(mut items: Vec<Statement>) -> Statement {
+    match items.len() {
+        1 => items.pop().unwrap(),
+        _ => Statement::Concat(items),
+    }
+}
+
+#[doc(hidden)]
+pub fn __alternatives(items: Vec<Statement>) -> Statement {
+    items.into_iter().reduce(|lhs, rhs| lhs.or(rhs)).unwrap()
+}
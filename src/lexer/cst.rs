@@ -0,0 +1,266 @@
+use std::fmt;
+use std::rc::Rc;
+
+use super::instruction::State;
+
+/// What a leaf of the concrete syntax tree holds.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenKind {
+    /// Text matched by a reader.
+    Text,
+    /// Text skipped by the lexer's trivia rule.
+    Trivia,
+    /// Input the grammar did not consume.
+    Unmatched,
+}
+
+/// An immutable, position independent leaf. Green trees can be shared
+/// between versions of a document since they only know their own text.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GreenToken {
+    pub kind: TokenKind,
+    pub name: String,
+    pub text: String,
+}
+
+/// An immutable, position independent node. `width` is the length in bytes
+/// of all the text below it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GreenNode {
+    pub name: String,
+    pub width: usize,
+    pub children: Vec<GreenElement>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum GreenElement {
+    Node(Rc<GreenNode>),
+    Token(Rc<GreenToken>),
+}
+
+impl GreenElement {
+    pub fn width(&self) -> usize {
+        match self {
+            GreenElement::Node(node) => node.width,
+            GreenElement::Token(token) => token.text.len(),
+        }
+    }
+}
+
+impl GreenNode {
+    pub fn new(name: &str, children: Vec<GreenElement>) -> GreenNode {
+        GreenNode {
+            name: name.to_string(),
+            width: children.iter().map(GreenElement::width).sum(),
+            children,
+        }
+    }
+}
+
+/// A node of the red tree: a green node placed at an absolute offset, with a
+/// link to its parent. Red nodes are created on demand while walking.
+#[derive(Debug, Clone)]
+pub struct SyntaxNode {
+    green: Rc<GreenNode>,
+    offset: usize,
+    parent: Option<Rc<SyntaxNode>>,
+}
+
+/// A leaf of the red tree.
+#[derive(Debug, Clone)]
+pub struct SyntaxToken {
+    green: Rc<GreenToken>,
+    offset: usize,
+    parent: Rc<SyntaxNode>,
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: GreenNode) -> SyntaxNode {
+        SyntaxNode {
+            green: Rc::new(green),
+            offset: 0,
+            parent: None,
+        }
+    }
+
+    pub fn green(&self) -> &GreenNode {
+        &self.green
+    }
+
+    pub fn name(&self) -> &str {
+        &self.green.name
+    }
+
+    pub fn start(&self) -> usize {
+        self.offset
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.green.width
+    }
+
+    pub fn parent(&self) -> Option<&SyntaxNode> {
+        self.parent.as_deref()
+    }
+
+    pub fn children(&self) -> Vec<SyntaxElement> {
+        let parent = Rc::new(self.clone());
+        let mut offset = self.offset;
+        self.green
+            .children
+            .iter()
+            .map(|child| {
+                let element = match child {
+                    GreenElement::Node(green) => SyntaxElement::Node(SyntaxNode {
+                        green: green.clone(),
+                        offset,
+                        parent: Some(parent.clone()),
+                    }),
+                    GreenElement::Token(green) => SyntaxElement::Token(SyntaxToken {
+                        green: green.clone(),
+                        offset,
+                        parent: parent.clone(),
+                    }),
+                };
+                offset += child.width();
+                element
+            })
+            .collect()
+    }
+
+    /// Every leaf below this node, in input order.
+    pub fn tokens(&self) -> Vec<SyntaxToken> {
+        let mut tokens = vec![];
+        for child in self.children() {
+            match child {
+                SyntaxElement::Node(node) => tokens.extend(node.tokens()),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+        tokens
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.tokens() {
+            write!(f, "{}", token.text())?;
+        }
+        Ok(())
+    }
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> TokenKind {
+        self.green.kind
+    }
+
+    pub fn name(&self) -> &str {
+        &self.green.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.green.text
+    }
+
+    pub fn start(&self) -> usize {
+        self.offset
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.green.text.len()
+    }
+
+    pub fn parent(&self) -> &SyntaxNode {
+        &self.parent
+    }
+}
+
+/// Builds a lossless tree from the capture states of one or more lexer VM
+/// runs over `input`. Input that no state covers becomes an unnamed text
+/// leaf, and input after the last state becomes an `Unmatched` leaf, so the
+/// leaves always concatenate back to `input`.
+pub fn build(states: Vec<State>, input: &str) -> SyntaxNode {
+    let mut cursor = 0;
+    let mut children = elements(states, input, &mut cursor);
+    if cursor < input.len() {
+        children.push(leaf(TokenKind::Unmatched, "", &input[cursor..]));
+    }
+    SyntaxNode::new_root(GreenNode::new("", children))
+}
+
+fn elements(states: Vec<State>, input: &str, cursor: &mut usize) -> Vec<GreenElement> {
+    let mut children = vec![];
+    for state in states {
+        match state {
+            State::StartCapture(_, _)
+            | State::Transform(_)
+            | State::PushMode(_)
+            | State::PopMode => {}
+            State::Text(text, start, end) => {
+                if let Some(gap) = gap(input, cursor, start) {
+                    children.push(gap);
+                }
+                if start >= *cursor {
+                    children.push(leaf(TokenKind::Text, "", text));
+                    *cursor = end;
+                }
+            }
+            State::Trivia(tokens) => {
+                for token in tokens {
+                    if let Some(gap) = gap(input, cursor, token.start) {
+                        children.push(gap);
+                    }
+                    if token.start >= *cursor {
+                        children.push(leaf(TokenKind::Trivia, &token.name, token.text));
+                        *cursor = token.end;
+                    }
+                }
+            }
+            State::List(name, list) => {
+                if let Some(gap) = first_offset(&list).and_then(|start| gap(input, cursor, start)) {
+                    children.push(gap);
+                }
+                let inner = elements(list, input, cursor);
+                if !inner.is_empty() {
+                    children.push(GreenElement::Node(Rc::new(GreenNode::new(&name, inner))));
+                }
+            }
+        }
+    }
+    children
+}
+
+fn first_offset(states: &[State]) -> Option<usize> {
+    states.iter().find_map(|state| match state {
+        State::StartCapture(_, _) | State::Transform(_) | State::PushMode(_) | State::PopMode => {
+            None
+        }
+        State::Text(_, start, _) => Some(*start),
+        State::Trivia(tokens) => tokens.first().map(|t| t.start),
+        State::List(_, list) => first_offset(list),
+    })
+}
+
+fn gap(input: &str, cursor: &mut usize, start: usize) -> Option<GreenElement> {
+    if start <= *cursor {
+        return None;
+    }
+    let text = &input[*cursor..start];
+    *cursor = start;
+    Some(leaf(TokenKind::Text, "", text))
+}
+
+fn leaf(kind: TokenKind, name: &str, text: &str) -> GreenElement {
+    GreenElement::Token(Rc::new(GreenToken {
+        kind,
+        name: name.to_string(),
+        text: text.to_string(),
+    }))
+}
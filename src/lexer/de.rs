@@ -0,0 +1,535 @@
+use std::cell::RefCell;
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize};
+
+use super::instruction::Token;
+use super::value::Value;
+
+/// Deserializes `T` from a saved token. Struct fields are read from the named
+/// children of the token: a field whose name is repeated becomes a `Vec`, a
+/// missing one an `Option`'s `None` or an empty `Vec`, and leaves are read
+/// from the token's `value` when it has one and parsed from its text
+/// otherwise. Other missing fields are errors unless they have a
+/// `#[serde(default)]`.
+pub fn from_tokens<'src, T: Deserialize<'src>>(token: &Token<'src>) -> Result<T, Error> {
+    // a missing field is only known not to be a sequence or an option once
+    // it fails, so it is left out of the next attempt for its default to
+    // apply, if it has one
+    let skipped = RefCell::new(vec![]);
+    loop {
+        let result = T::deserialize(TokenDeserializer {
+            token,
+            skipped: &skipped,
+        });
+        match result {
+            Err(Error::Missing {
+                field,
+                token: Some(at),
+            }) if !skipped.borrow().contains(&(at.clone(), field.clone())) => {
+                skipped.borrow_mut().push((at, field));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Error {
+    /// A struct field without children or a default, with the name and
+    /// offset of the token it is missing from once known.
+    Missing {
+        field: String,
+        token: Option<(String, usize)>,
+    },
+    /// Any other error, such as text that does not parse.
+    Message(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Missing {
+                field,
+                token: Some((name, offset)),
+            } => write!(f, "Missing {} in {} at offset {}", field, name, offset),
+            Error::Missing { field, token: None } => write!(f, "Missing {}", field),
+            Error::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Message(msg.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Error {
+        Error::Missing {
+            field: field.to_string(),
+            token: None,
+        }
+    }
+}
+
+/// The fields left out of structs, by the name and offset of their token.
+type Skipped = RefCell<Vec<((String, usize), String)>>;
+
+fn named<'a, 'src>(token: &'a Token<'src>) -> impl Iterator<Item = &'a Token<'src>> {
+    token.children.iter().filter(|c| !c.name.is_empty())
+}
+
+#[derive(Clone, Copy)]
+struct TokenDeserializer<'a, 'src> {
+    token: &'a Token<'src>,
+    skipped: &'a Skipped,
+}
+
+impl<'a, 'src> TokenDeserializer<'a, 'src> {
+    /// Deserializes `token`, a token under this one.
+    fn child(self, token: &'a Token<'src>) -> Self {
+        TokenDeserializer { token, ..self }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, what: &str) -> Result<T, Error> {
+        self.token.text.trim().parse().map_err(|_| {
+            Error::Message(format!(
+                "Expected {} in {} at offset {}",
+                what, self.token.name, self.token.start
+            ))
+        })
+    }
+
+    fn skips(&self, field: &str) -> bool {
+        let at = (self.token.name.clone(), self.token.start);
+        self.skipped
+            .borrow()
+            .iter()
+            .any(|(token, f)| *token == at && f == field)
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident $what:literal,)*) => {
+        $(
+            fn $method<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+                match &self.token.value {
+                    Some(value) => ValueDeserializer(value).deserialize_any(visitor),
+                    None => visitor.$visit(self.parse($what)?),
+                }
+            }
+        )*
+    };
+}
+
+impl<'src> de::Deserializer<'src> for TokenDeserializer<'_, 'src> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.token.value {
+            Some(value) => ValueDeserializer(value).deserialize_any(visitor),
+            None if named(self.token).next().is_some() => self.deserialize_map(visitor),
+            None => visitor.visit_borrowed_str(self.token.text),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool "a boolean",
+        deserialize_i8 => visit_i8 "an integer",
+        deserialize_i16 => visit_i16 "an integer",
+        deserialize_i32 => visit_i32 "an integer",
+        deserialize_i64 => visit_i64 "an integer",
+        deserialize_u8 => visit_u8 "an integer",
+        deserialize_u16 => visit_u16 "an integer",
+        deserialize_u32 => visit_u32 "an integer",
+        deserialize_u64 => visit_u64 "an integer",
+        deserialize_f32 => visit_f32 "a number",
+        deserialize_f64 => visit_f64 "a number",
+        deserialize_char => visit_char "a character",
+    }
+
+    fn deserialize_str<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.token.value {
+            Some(Value::Str(s)) => visitor.visit_string(s.clone()),
+            _ => visitor.visit_borrowed_str(self.token.text),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.token.text.as_bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'src>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'src>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.token.value {
+            Some(value @ Value::List(_)) => ValueDeserializer(value).deserialize_any(visitor),
+            _ => visitor.visit_seq(de::value::SeqDeserializer::new(
+                named(self.token).map(|token| self.child(token)),
+            )),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'src>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'src>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut keys: Vec<&str> = vec![];
+        for child in named(self.token) {
+            if !keys.contains(&child.name.as_str()) {
+                keys.push(&child.name);
+            }
+        }
+        visitor.visit_map(Fields {
+            parent: self,
+            keys: keys.into_iter(),
+            current: "",
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'src>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let keys = fields.iter().copied().filter(|field| !self.skips(field));
+        let result = visitor.visit_map(Fields {
+            parent: self,
+            keys,
+            current: "",
+        });
+        result.map_err(|e| match e {
+            Error::Missing { field, token: None } if fields.contains(&field.as_str()) => {
+                Error::Missing {
+                    field,
+                    token: Some((self.token.name.clone(), self.token.start)),
+                }
+            }
+            e => e,
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'src>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(Variant(self))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+impl<'a, 'src> IntoDeserializer<'src, Error> for TokenDeserializer<'a, 'src> {
+    type Deserializer = TokenDeserializer<'a, 'src>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+/// The named children of a token, grouped by name.
+struct Fields<'a, 'src, K> {
+    parent: TokenDeserializer<'a, 'src>,
+    keys: K,
+    current: &'a str,
+}
+
+impl<'a, 'src, K: Iterator<Item = &'a str>> de::MapAccess<'src> for Fields<'a, 'src, K> {
+    type Error = Error;
+
+    fn next_key_seed<S: DeserializeSeed<'src>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        match self.keys.next() {
+            Some(key) => {
+                self.current = key;
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'src>>(&mut self, seed: S) -> Result<S::Value, Error> {
+        let tokens = named(self.parent.token)
+            .filter(|c| c.name == self.current)
+            .collect();
+        seed.deserialize(Field {
+            parent: self.parent,
+            name: self.current,
+            tokens,
+        })
+    }
+}
+
+/// Every child of `parent` saved under `name`, which deserializes as a
+/// sequence, an option, or a single token. Without children it is an empty
+/// sequence or `None`.
+struct Field<'a, 'src> {
+    parent: TokenDeserializer<'a, 'src>,
+    name: &'a str,
+    tokens: Vec<&'a Token<'src>>,
+}
+
+impl<'a, 'src> Field<'a, 'src> {
+    fn single(&self) -> Result<TokenDeserializer<'a, 'src>, Error> {
+        let parent = self.parent.token;
+        match self.tokens.as_slice() {
+            [token] => Ok(self.parent.child(token)),
+            [] => Err(Error::Missing {
+                field: self.name.to_string(),
+                token: Some((parent.name.clone(), parent.start)),
+            }),
+            [_, second, ..] => Err(Error::Message(format!(
+                "Unexpected second {} in {} at offset {}",
+                self.name, parent.name, second.start
+            ))),
+        }
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'src> de::Deserializer<'src> for Field<'_, 'src> {
+    type Error = Error;
+
+    forward_to_single! {
+        deserialize_any deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_i64 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64 deserialize_char deserialize_str deserialize_string
+        deserialize_bytes deserialize_byte_buf deserialize_unit deserialize_map
+        deserialize_identifier
+    }
+
+    fn deserialize_option<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.tokens.is_empty() {
+            true => visitor.visit_none(),
+            false => visitor.visit_some(self.single()?),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        let parent = self.parent;
+        visitor.visit_seq(de::value::SeqDeserializer::new(
+            self.tokens.into_iter().map(|token| parent.child(token)),
+        ))
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'src>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'src>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'src>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'src>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'src>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'src>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'src>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// An enum is read from a token with a single named child, whose name is
+/// the variant, or from the text of a token without named children for unit
+/// variants.
+struct Variant<'a, 'src>(TokenDeserializer<'a, 'src>);
+
+impl<'a, 'src> de::EnumAccess<'src> for Variant<'a, 'src> {
+    type Error = Error;
+    type Variant = Content<'a, 'src>;
+
+    fn variant_seed<S: DeserializeSeed<'src>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self::Variant), Error> {
+        match named(self.0.token).next() {
+            Some(child) => {
+                let variant = seed.deserialize(child.name.as_str().into_deserializer())?;
+                Ok((variant, Content(Some(self.0.child(child)))))
+            }
+            None => {
+                let variant = seed.deserialize(self.0.token.text.into_deserializer())?;
+                Ok((variant, Content(None)))
+            }
+        }
+    }
+}
+
+/// What follows the variant name: the named child, if any.
+struct Content<'a, 'src>(Option<TokenDeserializer<'a, 'src>>);
+
+impl<'src> de::VariantAccess<'src> for Content<'_, 'src> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'src>>(self, seed: S) -> Result<S::Value, Error> {
+        match self.0 {
+            Some(token) => seed.deserialize(token),
+            None => Err(Error::Message("Expected a newtype variant".to_string())),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'src>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Some(token) => de::Deserializer::deserialize_tuple(token, len, visitor),
+            None => Err(Error::Message("Expected a tuple variant".to_string())),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'src>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.0 {
+            Some(token) => de::Deserializer::deserialize_struct(token, "", fields, visitor),
+            None => Err(Error::Message("Expected a struct variant".to_string())),
+        }
+    }
+}
+
+/// A converted `Token::value`.
+struct ValueDeserializer<'a>(&'a Value);
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Int(i) => visitor.visit_i64(*i),
+            Value::Float(x) => visitor.visit_f64(*x),
+            Value::Str(s) => visitor.visit_string(s.clone()),
+            Value::Bool(b) => visitor.visit_bool(*b),
+            Value::List(items) => visitor.visit_seq(de::value::SeqDeserializer::new(
+                items.iter().map(ValueDeserializer),
+            )),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'a> IntoDeserializer<'_, Error> for ValueDeserializer<'a> {
+    type Deserializer = ValueDeserializer<'a>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::Write;
+
+use super::instruction::Token;
+use super::value::Value;
+
+/// What `Token::to_json` and `Token::to_sexpr` include besides the names,
+/// values and children of the tokens.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DumpOptions {
+    /// Byte offsets where each token starts and ends.
+    pub spans: bool,
+    /// The input text each token covers.
+    pub text: bool,
+}
+
+impl Default for DumpOptions {
+    fn default() -> DumpOptions {
+        DumpOptions {
+            spans: true,
+            text: true,
+        }
+    }
+}
+
+impl Token<'_> {
+    /// Pretty-prints the token tree as JSON. Keys always come in the same
+    /// order and empty fields are left out, so dumps can be compared as text.
+    pub fn to_json(&self, options: DumpOptions) -> String {
+        let mut out = String::new();
+        json(self, options, 0, &mut out);
+        out
+    }
+
+    /// Pretty-prints the token tree as an S-expression, one token per line:
+    /// `(name :span 0 3 :text "abc" :value 1 children...)`. Unsaved tokens
+    /// are named `_`.
+    pub fn to_sexpr(&self, options: DumpOptions) -> String {
+        let mut out = String::new();
+        sexpr(self, options, 0, &mut out);
+        out
+    }
+}
+
+fn json(token: &Token, options: DumpOptions, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth + 1);
+    let mut fields = vec![format!("\"name\": {}", quote(&token.name))];
+    if options.spans {
+        fields.push(format!("\"start\": {}", token.start));
+        fields.push(format!("\"end\": {}", token.end));
+    }
+    if options.text {
+        fields.push(format!("\"text\": {}", quote(token.text)));
+    }
+    if let Some(value) = &token.value {
+        fields.push(format!("\"value\": {}", json_value(value)));
+    }
+    if !token.children.is_empty() {
+        let mut children = String::from("\"children\": [");
+        for (i, child) in token.children.iter().enumerate() {
+            children.push_str(if i == 0 { "\n" } else { ",\n" });
+            children.push_str(&"  ".repeat(depth + 2));
+            json(child, options, depth + 2, &mut children);
+        }
+        let _ = write!(children, "\n{}]", indent);
+        fields.push(children);
+    }
+    out.push_str("{\n");
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push_str(",\n");
+        }
+        out.push_str(&indent);
+        out.push_str(field);
+    }
+    let _ = write!(out, "\n{}}}", "  ".repeat(depth));
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Float(x) if !x.is_finite() => "null".to_string(),
+        Value::Str(s) => quote(s),
+        Value::List(items) => {
+            let items: Vec<String> = items.iter().map(json_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+fn sexpr(token: &Token, options: DumpOptions, depth: usize, out: &mut String) {
+    out.push('(');
+    out.push_str(if token.name.is_empty() {
+        "_"
+    } else {
+        &token.name
+    });
+    if options.spans {
+        let _ = write!(out, " :span {} {}", token.start, token.end);
+    }
+    if options.text {
+        let _ = write!(out, " :text {}", quote(token.text));
+    }
+    if let Some(value) = &token.value {
+        let _ = write!(out, " :value {}", sexpr_value(value));
+    }
+    for child in &token.children {
+        let _ = write!(out, "\n{}", "  ".repeat(depth + 1));
+        sexpr(child, options, depth + 1, out);
+    }
+    out.push(')');
+}
+
+fn sexpr_value(value: &Value) -> String {
+    match value {
+        Value::Str(s) => quote(s),
+        Value::List(items) => {
+            let items: Vec<String> = items.iter().map(sexpr_value).collect();
+            format!("[{}]", items.join(" "))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// Quotes `text` as a JSON string, which S-expression readers accept too.
+fn quote(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,200 @@
+use super::instruction::{LexError, Token};
+use super::{Lexer, Mode, Tokens};
+
+/// A text edit: the bytes `start..end` of the old input are replaced with
+/// `text`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+impl Edit {
+    pub fn new(start: usize, end: usize, text: &str) -> Edit {
+        Edit {
+            start,
+            end,
+            text: text.to_string(),
+        }
+    }
+
+    /// The input after the edit, or an error if `start..end` is not a range
+    /// of `old` between two characters.
+    pub fn apply(&self, old: &str) -> Result<String, LexError> {
+        if self.start > self.end
+            || self.end > old.len()
+            || !old.is_char_boundary(self.start)
+            || !old.is_char_boundary(self.end)
+        {
+            return Err(mismatch(self));
+        }
+        Ok(format!(
+            "{}{}{}",
+            &old[..self.start],
+            self.text,
+            &old[self.end..]
+        ))
+    }
+}
+
+/// The tokens of a whole input along with the lexer states needed to lex it
+/// again after an edit, see `Lexer::relex`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lexed<'src> {
+    pub tokens: Vec<Token<'src>>,
+    checkpoints: Vec<Checkpoint>,
+    len: usize,
+}
+
+/// A point between two rule runs where the lexer can be restarted: nothing
+/// depends on the input before `offset` except the mode stack.
+#[derive(Debug, PartialEq, Clone)]
+struct Checkpoint {
+    offset: usize,
+    /// Index of the first token lexed after `offset`.
+    token: usize,
+    modes: Vec<usize>,
+}
+
+impl Lexer {
+    /// Lexes `input` like `lex`, keeping what `relex` needs to reuse the
+    /// result after an edit.
+    pub fn lex_incremental<'src>(&self, input: &'src str) -> Result<Lexed<'src>, LexError> {
+        let mut checkpoints = vec![];
+        let tokens = drive(self.tokens(input), vec![], &mut checkpoints, |_| false)?;
+        Ok(Lexed {
+            tokens,
+            checkpoints,
+            len: input.len(),
+        })
+    }
+
+    /// Lexes `input`, the text of `old` after `edit`, again. Tokens before
+    /// the edit are reused and lexing restarts shortly before it, then stops
+    /// as soon as the lexer reaches a position after the edit where it was in
+    /// the same state before, and reuses the rest. Lookaheads are assumed not
+    /// to reach past the token after the one they belong to. Lexers in
+    /// `Mode::Structure` or tracking indentation lex the whole input again.
+    pub fn relex<'src>(
+        &self,
+        old: &Lexed,
+        edit: &Edit,
+        input: &'src str,
+    ) -> Result<Lexed<'src>, LexError> {
+        let edited = edit.start + edit.text.len();
+        if edit.start > edit.end
+            || edit.end > old.len
+            || old.len - (edit.end - edit.start) + edit.text.len() != input.len()
+            || !input.is_char_boundary(edit.start)
+            || !input.is_char_boundary(edited)
+            || input[edit.start..edited] != edit.text
+        {
+            return Err(mismatch(edit));
+        }
+        if self.mode == Mode::Structure || self.indentation {
+            return self.lex_incremental(input);
+        }
+        // the run before the edit may have looked into it, so restart one
+        // checkpoint earlier
+        let restart = old
+            .checkpoints
+            .iter()
+            .rposition(|c| c.offset < edit.start)
+            .map_or(0, |i| i.saturating_sub(1));
+        let from = &old.checkpoints[restart];
+        let prefix = old.tokens[..from.token]
+            .iter()
+            .map(|t| shift(t, input, 0))
+            .collect();
+        let mut checkpoints = old.checkpoints[..restart].to_vec();
+
+        let delta = input.len() as isize - old.len as isize;
+        let rest = &old.checkpoints[restart + 1..];
+        let mut tokens = self.tokens(input);
+        tokens.offset = from.offset;
+        tokens.modes = from.modes.clone();
+        let mut reused = None;
+        let mut lexed = drive(tokens, prefix, &mut checkpoints, |t| {
+            if t.offset < edited {
+                return false;
+            }
+            // the last token takes the trailing trivia, so at least one
+            // token is reused
+            let offset = (t.offset as isize - delta) as usize;
+            reused = rest.iter().position(|c| {
+                c.offset == offset && c.modes == t.modes && c.token < old.tokens.len()
+            });
+            reused.is_some()
+        })?;
+        if let Some(i) = reused {
+            let base = rest[i].token;
+            let first = lexed.len();
+            lexed.extend(old.tokens[base..].iter().map(|t| shift(t, input, delta)));
+            checkpoints.extend(rest[i..].iter().map(|c| Checkpoint {
+                offset: (c.offset as isize + delta) as usize,
+                token: c.token - base + first,
+                modes: c.modes.clone(),
+            }));
+        }
+        Ok(Lexed {
+            tokens: lexed,
+            checkpoints,
+            len: input.len(),
+        })
+    }
+}
+
+/// Runs `tokens` to the end, recording a checkpoint before every rule run
+/// that starts with no pending trivia, or until `stop` finds one it can
+/// reuse. Returns `out` followed by the tokens lexed.
+fn drive<'src>(
+    mut tokens: Tokens<'_, 'src>,
+    mut out: Vec<Token<'src>>,
+    checkpoints: &mut Vec<Checkpoint>,
+    mut stop: impl FnMut(&Tokens<'_, 'src>) -> bool,
+) -> Result<Vec<Token<'src>>, LexError> {
+    loop {
+        if tokens.trivia.is_empty() {
+            if stop(&tokens) {
+                break;
+            }
+            checkpoints.push(Checkpoint {
+                offset: tokens.offset,
+                token: out.len() + tokens.pending.len(),
+                modes: tokens.modes.clone(),
+            });
+        }
+        if tokens.done {
+            break;
+        }
+        tokens.step();
+        if let Some(e) = tokens.error.take() {
+            return Err(e);
+        }
+    }
+    out.extend(tokens.pending);
+    Ok(out)
+}
+
+/// Moves a token lexed from an older input by `delta` bytes, borrowing its
+/// text from `input`.
+fn shift<'src>(token: &Token, input: &'src str, delta: isize) -> Token<'src> {
+    let start = (token.start as isize + delta) as usize;
+    let end = (token.end as isize + delta) as usize;
+    let all = |tokens: &[Token]| tokens.iter().map(|t| shift(t, input, delta)).collect();
+    Token {
+        name: token.name.clone(),
+        text: &input[start..end],
+        start,
+        end,
+        value: token.value.clone(),
+        children: all(&token.children),
+        leading: all(&token.leading),
+        trailing: all(&token.trailing),
+    }
+}
+
+fn mismatch(edit: &Edit) -> LexError {
+    LexError::new(edit.start, "Edit does not match the input")
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+
+use super::trie::Trie;
+use super::value::{convert, Hooks, Transform, Value};
 use crate::regex::Regex;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -5,23 +9,139 @@ pub enum Statement {
     Reader(Regex),
     Concat(Vec<Statement>),
     Alternation(Box<Statement>, Box<Statement>),
+    /// Matches any one of the statements, trying them in order and
+    /// backtracking into the later ones. Without any it never matches.
+    Choice(Vec<Statement>),
+    /// Matches the longest of the keywords at the current position.
+    Keywords(Vec<String>),
+    /// PEG ordered choice: the first alternative that matches is committed
+    /// to, and the later ones are never tried even if what follows fails.
+    /// Without any alternatives it never matches.
+    Ordered(Vec<Statement>),
+    /// Maximal munch: runs every alternative at the current position and
+    /// keeps the one that consumed the most input, earlier alternatives
+    /// winning ties. The chosen alternative is never backtracked into.
+    Longest(Vec<Statement>),
     ZeroOrOne(Box<Statement>),
     ZeroOrMore(Box<Statement>),
     OneOrMore(Box<Statement>),
+    /// Matches `inner` at least `min` and at most `max` times, or without an
+    /// upper bound when `max` is `None`.
+    Repeat {
+        inner: Box<Statement>,
+        min: usize,
+        max: Option<usize>,
+    },
+    /// Runs `then` when `cond` matches at the current position, otherwise
+    /// runs `otherwise` if there is one. `cond` never consumes input.
     Condition {
         cond: Box<Statement>,
-        inner: Box<Statement>,
+        then: Box<Statement>,
+        otherwise: Option<Box<Statement>>,
     },
+    /// Succeeds without consuming input when the statement does not match.
+    Not(Box<Statement>),
+    /// Succeeds without consuming input when the statement matches.
+    And(Box<Statement>),
     Save(String, Box<Statement>),
+    /// Like `Save`, also converting the saved text into the token's `value`.
+    SaveWith(String, Vec<Transform>, Box<Statement>),
+    /// At least `min` `item`s separated by `sep`, optionally followed by one
+    /// more `sep` when `trailing` is set.
+    SepBy {
+        item: Box<Statement>,
+        sep: Box<Statement>,
+        trailing: bool,
+        min: usize,
+    },
+    /// `inner` between `open` and `close`. When the lexer has a `Recovery`
+    /// and `inner` is not followed by `close`, the input up to the next
+    /// `close` is saved as an `ERROR_TOKEN` instead, so one bad block does not
+    /// fail the whole run. When there is no `close` left and the lexer
+    /// recovers with `Recovery::InsertDelimiters`, a zero width
+    /// `MISSING_TOKEN` stands in for it.
+    Delimited {
+        open: Box<Statement>,
+        inner: Box<Statement>,
+        close: Box<Statement>,
+    },
+    PushMode(String),
+    PopMode,
+    /// Matches the rule `name` defined with `Lexer::with_rule`. Rules may
+    /// refer to themselves, also as their leftmost item.
+    Rule(String),
+    /// An expression of `atom`s combined by `operators`, each use of an
+    /// operator saved as a token named after it that holds its operands and
+    /// the operator in input order.
+    Precedence {
+        atom: Box<Statement>,
+        operators: Vec<OpDef>,
+    },
+}
+
+/// Where an operator of a `Statement::Precedence` goes relative to its
+/// operands, and how a chain of infix operators of the same precedence
+/// nests.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fixity {
+    Prefix,
+    /// `1-2-3` reads as `(1-2)-3`.
+    InfixLeft,
+    /// `2^3^4` reads as `2^(3^4)`.
+    InfixRight,
+    Postfix,
+}
+
+/// An operator of a `Statement::Precedence`, matched by `op`. Operators with
+/// a higher `precedence` bind tighter.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OpDef {
+    pub name: String,
+    pub op: Statement,
+    pub fixity: Fixity,
+    pub precedence: usize,
+}
+
+impl OpDef {
+    pub fn new(name: &str, op: Statement, fixity: Fixity, precedence: usize) -> OpDef {
+        OpDef {
+            name: name.to_string(),
+            op,
+            fixity,
+            precedence,
+        }
+    }
+}
+
+/// A compiled `OpDef`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Operator {
+    pub name: String,
+    pub program: Vec<Instruction>,
+    pub fixity: Fixity,
+    pub precedence: usize,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Instruction {
     Match(Regex),
+    Keywords(Trie),
     Split(usize, usize),
+    Choice(usize),
+    Commit(usize),
+    Longest(Vec<Vec<Instruction>>),
     Jmp(usize),
+    PushCounter,
+    Repeat {
+        min: usize,
+        max: Option<usize>,
+        exit: usize,
+    },
+    Increment(usize),
+    PopCounter,
     JumpF(usize),
     Save(String),
+    Transform(Vec<Transform>),
     StartCapture(String),
     EndCapture(String),
     Flag,
@@ -29,57 +149,257 @@ pub enum Instruction {
     Carry,
     Push(usize),
     Pop,
+    Fail,
+    Recover,
+    Insert,
+    PushMode(String),
+    PopMode,
+    Call(String),
+    Precedence {
+        atom: Vec<Instruction>,
+        operators: Vec<Operator>,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum State {
+pub enum State<'src> {
     StartCapture(String, usize),
-    Text(String, usize, usize),
-    List(String, Vec<State>),
+    Text(&'src str, usize, usize),
+    Trivia(Vec<Token<'src>>),
+    List(String, Vec<State<'src>>),
+    /// Conversions for the value of the `List` it is the last state of.
+    Transform(Vec<Transform>),
+    PushMode(String),
+    PopMode,
+}
+
+/// A token whose text borrows from the lexed input. `leading` and `trailing`
+/// hold the trivia skipped around it when the lexer attaches trivia, and
+/// `value` what `Statement::SaveWith` converted its text into.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token<'src> {
+    pub name: String,
+    pub text: &'src str,
+    pub start: usize,
+    pub end: usize,
+    pub value: Option<Value>,
+    pub children: Vec<Token<'src>>,
+    pub leading: Vec<Token<'src>>,
+    pub trailing: Vec<Token<'src>>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Token {
+/// A token that owns its text, for handing results across the wasm boundary
+/// or keeping them after the input is dropped.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OwnedToken {
     pub name: String,
     pub text: String,
     pub start: usize,
     pub end: usize,
-    pub children: Vec<Token>,
+    pub value: Option<Value>,
+    pub children: Vec<OwnedToken>,
+    pub leading: Vec<OwnedToken>,
+    pub trailing: Vec<OwnedToken>,
 }
 
-impl State {
-    pub fn into_token(self) -> Option<Token> {
-        match self {
-            State::Text(text, start, end) => Some(Token {
-                name: "".to_string(),
-                text,
-                start,
-                end,
-                children: vec![],
-            }),
-            State::StartCapture(_, _) => None,
-            State::List(name, list) => {
-                let children: Vec<Token> = list
-                    .into_iter()
-                    .filter_map(|state| state.into_token())
-                    .collect();
+impl<'src> Token<'src> {
+    pub fn new(name: &str, text: &'src str, start: usize, end: usize) -> Token<'src> {
+        Token {
+            name: name.to_string(),
+            text,
+            start,
+            end,
+            value: None,
+            children: vec![],
+            leading: vec![],
+            trailing: vec![],
+        }
+    }
 
+    pub fn to_owned_token(&self) -> OwnedToken {
+        OwnedToken {
+            name: self.name.clone(),
+            text: self.text.to_string(),
+            start: self.start,
+            end: self.end,
+            value: self.value.clone(),
+            children: self.children.iter().map(Token::to_owned_token).collect(),
+            leading: self.leading.iter().map(Token::to_owned_token).collect(),
+            trailing: self.trailing.iter().map(Token::to_owned_token).collect(),
+        }
+    }
+}
+
+impl<'src> State<'src> {
+    pub fn into_token(self, input: &'src str) -> Option<Token<'src>> {
+        self.build(input, &Hooks::default())
+    }
+
+    fn build(self, input: &'src str, hooks: &Hooks) -> Option<Token<'src>> {
+        match self {
+            State::Text(text, start, end) => Some(Token::new("", text, start, end)),
+            State::StartCapture(_, _)
+            | State::Trivia(_)
+            | State::Transform(_)
+            | State::PushMode(_)
+            | State::PopMode => None,
+            State::List(name, mut list) => {
+                let transforms = match list.last() {
+                    Some(State::Transform(_)) => match list.pop() {
+                        Some(State::Transform(transforms)) => Some(transforms),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                let mut children = collect(list, input, false, hooks);
                 if children.is_empty() {
                     return None;
                 }
                 let start = children.iter().map(|t| t.start).min().unwrap_or(0);
                 let end = children.iter().map(|t| t.end).max().unwrap_or(0);
 
-                let text = children.iter().map(|t| t.text.as_str()).collect::<String>();
+                // trivia belongs to the outermost token that starts or ends with it
+                let leading = std::mem::take(&mut children[0].leading);
+                let trailing = children
+                    .last_mut()
+                    .map(|t| std::mem::take(&mut t.trailing))
+                    .unwrap_or_default();
+
+                let value = transforms.and_then(|transforms| {
+                    let values: Vec<Value> = children
+                        .iter()
+                        .filter(|c| !c.name.is_empty())
+                        .map(|c| c.value.clone().unwrap_or(Value::Str(c.text.to_string())))
+                        .collect();
+                    convert(&transforms, &input[start..end], &values, hooks)
+                });
 
                 Some(Token {
                     name: name.to_string(),
-                    text,
+                    text: &input[start..end],
                     start,
                     end,
+                    value,
                     children,
+                    leading,
+                    trailing,
                 })
             }
         }
     }
 }
+
+/// Converts the states left by a run of the lexer VM into the saved tokens,
+/// attaching recorded trivia to the neighbouring tokens.
+pub fn into_tokens<'src>(states: Vec<State<'src>>, input: &'src str) -> Vec<Token<'src>> {
+    collect(states, input, true, &Hooks::default())
+}
+
+/// Like `into_tokens`, running `hooks` for `Transform::Custom` conversions.
+pub fn into_tokens_with<'src>(
+    states: Vec<State<'src>>,
+    input: &'src str,
+    hooks: &Hooks,
+) -> Vec<Token<'src>> {
+    collect(states, input, true, hooks)
+}
+
+fn collect<'src>(
+    states: Vec<State<'src>>,
+    input: &'src str,
+    saved_only: bool,
+    hooks: &Hooks,
+) -> Vec<Token<'src>> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut trivia: Vec<Token> = vec![];
+    for state in states {
+        match state {
+            State::Trivia(skipped) => trivia.extend(skipped),
+            State::Text(_, _, _) if saved_only => {}
+            state => {
+                if let Some(mut token) = state.build(input, hooks) {
+                    trivia.append(&mut token.leading);
+                    token.leading = std::mem::take(&mut trivia);
+                    tokens.push(token);
+                }
+            }
+        }
+    }
+    if let Some(last) = tokens.last_mut() {
+        last.trailing.append(&mut trivia);
+    }
+    tokens
+}
+
+/// Several alternatives of a `Statement::Longest`, or several rules of a
+/// lexer with `Select::Longest`, matched the same longest text at `offset`.
+/// `alternatives` are their indices, the first one is the one that was kept.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ambiguity {
+    pub offset: usize,
+    pub len: usize,
+    pub alternatives: Vec<usize>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl LexError {
+    pub fn new(offset: usize, message: &str) -> LexError {
+        LexError {
+            offset,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Match(regex) => write!(f, "match /{}/", regex),
+            Instruction::Keywords(trie) => write!(f, "keywords {}", trie.words().join(" ")),
+            Instruction::Split(a, b) => write!(f, "split {} {}", a, b),
+            Instruction::Choice(a) => write!(f, "choice {}", a),
+            Instruction::Commit(a) => write!(f, "commit {}", a),
+            Instruction::Longest(alternatives) => write!(f, "longest {}", alternatives.len()),
+            Instruction::Jmp(a) => write!(f, "jmp {}", a),
+            Instruction::PushCounter => write!(f, "push_counter"),
+            Instruction::Repeat { min, max, exit } => match max {
+                Some(max) => write!(f, "repeat {} {} {}", min, max, exit),
+                None => write!(f, "repeat {} * {}", min, exit),
+            },
+            Instruction::Increment(a) => write!(f, "increment {}", a),
+            Instruction::PopCounter => write!(f, "pop_counter"),
+            Instruction::JumpF(a) => write!(f, "jump_f {}", a),
+            Instruction::Save(name) => write!(f, "save {}", name),
+            Instruction::Transform(transforms) => write!(f, "transform {:?}", transforms),
+            Instruction::StartCapture(name) => write!(f, "start_capture {}", name),
+            Instruction::EndCapture(name) => write!(f, "end_capture {}", name),
+            Instruction::Flag => write!(f, "flag"),
+            Instruction::UnFlag => write!(f, "unflag"),
+            Instruction::Carry => write!(f, "carry"),
+            Instruction::Push(a) => write!(f, "push {}", a),
+            Instruction::Pop => write!(f, "pop"),
+            Instruction::Fail => write!(f, "fail"),
+            Instruction::Recover => write!(f, "recover"),
+            Instruction::Insert => write!(f, "insert"),
+            Instruction::PushMode(name) => write!(f, "push_mode {}", name),
+            Instruction::PopMode => write!(f, "pop_mode"),
+            Instruction::Call(name) => write!(f, "call {}", name),
+            Instruction::Precedence { operators, .. } => {
+                write!(f, "precedence {}", operators.len())
+            }
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for LexError {}
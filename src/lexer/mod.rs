@@ -1,22 +1,794 @@
+pub mod analysis;
+pub mod builder;
+pub mod cst;
+#[cfg(feature = "serde")]
+pub mod de;
+pub mod dump;
+pub mod incremental;
 pub mod instruction;
+#[cfg(feature = "serde")]
+mod ser;
+pub mod trace;
+pub mod trie;
+pub mod value;
 pub mod vm;
-use instruction::{Instruction, Statement};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use instruction::{into_tokens_with, Ambiguity, Instruction, LexError, State, Statement, Token};
+use value::{Hooks, Value};
 use wasm_bindgen::prelude::wasm_bindgen;
 
+/// Name of the rule set a lexer starts in.
+pub const DEFAULT_MODE: &str = "default";
+
+/// Name of the tokens holding input the lexer skipped to recover from an
+/// error.
+pub const ERROR_TOKEN: &str = "error";
+
+/// Name of the zero width tokens standing in for a delimiter the lexer
+/// inserted to recover from an error.
+pub const MISSING_TOKEN: &str = "missing";
+
+/// Name of the zero width tokens the lexer emits where a line is indented
+/// deeper than the one before, see `Lexer::with_indentation`.
+pub const INDENT_TOKEN: &str = "indent";
+
+/// Name of the zero width tokens closing an indentation level.
+pub const DEDENT_TOKEN: &str = "dedent";
+
+/// How the lexer picks a rule in `Mode::Tokens` when several match.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Select {
+    /// The first rule that matches wins.
+    First,
+    /// The rule with the longest match wins, earlier rules break ties.
+    Longest,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    /// Runs a single grammar once over the whole input.
+    Structure,
+    /// Matches a set of token rules repeatedly until the end of the input.
+    Tokens(Select),
+}
+
+/// What the lexer does with the text matched by its trivia rule.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Trivia {
+    /// Skipped text is dropped.
+    Drop,
+    /// Skipped text is attached to the neighbouring tokens as `leading` and
+    /// `trailing` trivia.
+    Attach,
+}
+
+/// How the lexer carries on after input no rule matches. Every recovery is
+/// reported as an error, see `Lexer::lex_all`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Recovery {
+    /// Skips one character and emits it as an `ERROR_TOKEN`.
+    SkipChar,
+    /// Skips input up to and including the next match of one of the rules,
+    /// such as `;`, and emits it as a single `ERROR_TOKEN`. A lookahead like
+    /// `and(keyword)` stops right before the keyword instead.
+    Sync(Vec<Statement>),
+    /// Lets `Statement::Delimited` insert its missing closing delimiter as a
+    /// zero width `MISSING_TOKEN`.
+    InsertDelimiters,
+}
+
+/// How to skip input that no rule matches.
+#[derive(Debug, PartialEq, Clone)]
+enum Skip {
+    Char,
+    Sync(Vec<Vec<Instruction>>),
+}
+
 #[wasm_bindgen]
 #[derive(Debug, PartialEq, Clone)]
 pub struct Lexer {
-    program: Vec<Instruction>,
+    /// Named rule sets, the first one is the default lexer mode. A lexer in
+    /// `Mode::Structure` has a single set with a single program.
+    rule_sets: Vec<(String, Vec<Vec<Instruction>>)>,
+    mode: Mode,
+    trivia: Option<(Vec<Instruction>, Trivia)>,
+    hooks: Hooks,
+    skip: Option<Skip>,
+    insert_delimiters: bool,
+    memo: Option<usize>,
+    /// Rules `Statement::Rule` refers to by name.
+    rules: Vec<(String, Vec<Instruction>)>,
+    indentation: bool,
+    /// The statements the lexer was built from and what their analysis
+    /// found: the warnings, or the error every lex fails with.
+    grammar: analysis::Grammar,
+    analysis: Result<Vec<String>, String>,
 }
 
 impl Lexer {
+    /// Creates a lexer in `Mode::Structure` running `component` once over
+    /// the whole input. Fails when the grammar loops over a statement that
+    /// can match empty input, see `analysis`.
     pub fn new(component: Statement) -> Result<Lexer, String> {
+        let grammar = analysis::Grammar {
+            modes: vec![(DEFAULT_MODE.to_string(), vec![component.clone()])],
+            ..analysis::Grammar::default()
+        };
+        let warnings = analysis::check(&grammar, Mode::Structure)?;
         let mut program = Vec::new();
         vm::compile(&component, &mut program);
-        Ok(Lexer { program })
+        Ok(Lexer {
+            rule_sets: vec![(DEFAULT_MODE.to_string(), vec![program])],
+            mode: Mode::Structure,
+            trivia: None,
+            hooks: Hooks::default(),
+            skip: None,
+            insert_delimiters: false,
+            memo: None,
+            rules: vec![],
+            indentation: false,
+            grammar,
+            analysis: Ok(warnings),
+        })
     }
 
-    pub fn lex(&self, input: &str) {
-        vm::execute(&self.program, input);
+    /// Creates a lexer in `Mode::Tokens` where every rule is tried at the
+    /// current position and the tokens it saves are appended to the stream.
+    pub fn tokenizer(rules: Vec<Statement>, select: Select) -> Result<Lexer, String> {
+        if rules.is_empty() {
+            return Err("Expected at least one rule".to_string());
+        }
+        let grammar = analysis::Grammar {
+            modes: vec![(DEFAULT_MODE.to_string(), rules.clone())],
+            ..analysis::Grammar::default()
+        };
+        let warnings = analysis::check(&grammar, Mode::Tokens(select))?;
+        Ok(Lexer {
+            rule_sets: vec![(DEFAULT_MODE.to_string(), compile_rules(&rules))],
+            mode: Mode::Tokens(select),
+            trivia: None,
+            hooks: Hooks::default(),
+            skip: None,
+            insert_delimiters: false,
+            memo: None,
+            rules: vec![],
+            indentation: false,
+            grammar,
+            analysis: Ok(warnings),
+        })
     }
+
+    /// Adds a named lexer mode to a lexer in `Mode::Tokens`. Rules switch to
+    /// it with `Statement::PushMode` and back with `Statement::PopMode`, and
+    /// while it is on top of the mode stack only its rules are tried.
+    /// Adding a mode with an existing name replaces its rules.
+    pub fn with_mode(mut self, name: &str, rules: Vec<Statement>) -> Lexer {
+        replace(&mut self.rule_sets, name, compile_rules(&rules));
+        replace(&mut self.grammar.modes, name, rules);
+        self.analyse()
+    }
+
+    /// Sets a trivia rule, such as whitespace or comments, that is skipped
+    /// automatically between tokens. In `Mode::Structure` it is also skipped
+    /// before every reader of the grammar, except inside lookaheads, which
+    /// see the input as it is.
+    pub fn with_trivia(mut self, rule: Statement, trivia: Trivia) -> Lexer {
+        let mut program = Vec::new();
+        vm::compile(&rule, &mut program);
+        self.trivia = Some((program, trivia));
+        self.grammar.trivia = Some(rule);
+        self.analyse()
+    }
+
+    /// Registers a conversion run by `Transform::Custom(name)`. It receives
+    /// the value converted so far and returns `None` when it does not apply.
+    pub fn with_hook<F>(mut self, name: &str, hook: F) -> Lexer
+    where
+        F: Fn(&Value) -> Option<Value> + 'static,
+    {
+        self.hooks.insert(name, std::rc::Rc::new(hook));
+        self
+    }
+
+    /// Enables a recovery strategy. `Recovery::InsertDelimiters` combines
+    /// with either of the others, and a later `SkipChar` or `Sync` replaces
+    /// an earlier one.
+    pub fn with_recovery(mut self, recovery: Recovery) -> Lexer {
+        match recovery {
+            Recovery::SkipChar => {
+                self.skip = Some(Skip::Char);
+                self.grammar.sync = vec![];
+            }
+            Recovery::Sync(rules) => {
+                self.skip = Some(Skip::Sync(compile_rules(&rules)));
+                self.grammar.sync = rules;
+            }
+            Recovery::InsertDelimiters => self.insert_delimiters = true,
+        }
+        self.analyse()
+    }
+
+    /// Defines the rule `name` for `Statement::Rule` to refer to, from any
+    /// rule set, the trivia rule or other named rules including itself.
+    /// Rules may be left recursive, directly or through each other, and
+    /// build left associative trees. Referring to a rule that is not defined
+    /// fails to match. Defining a rule again replaces it.
+    pub fn with_rule(mut self, name: &str, rule: Statement) -> Lexer {
+        let mut program = Vec::new();
+        vm::compile(&rule, &mut program);
+        replace(&mut self.rules, name, program);
+        replace(&mut self.grammar.rules, name, rule);
+        self.analyse()
+    }
+
+    /// Tracks the indentation of lines in `Mode::Tokens`. Where a rule starts
+    /// at the first character of a line that is not a space or a tab, the
+    /// width of the spaces and tabs before it, each counting as one column,
+    /// is compared with a stack of open levels. A deeper line opens a level
+    /// with an `INDENT_TOKEN`, a shallower one closes levels with a
+    /// `DEDENT_TOKEN` each and must then line up with an open level, or the
+    /// lexer reports an inconsistent dedent. The tokens are zero width at the
+    /// start of the line's first token, and levels still open at the end of
+    /// the input are closed there. Blank lines and lines continuing a token
+    /// that spans several lines are ignored, as are lines holding only
+    /// trivia when the trivia rule skips newlines.
+    pub fn with_indentation(mut self) -> Lexer {
+        self.indentation = true;
+        self
+    }
+
+    /// Memoizes the result of every reader, maximal munch and named rule call
+    /// by offset, keeping at most `limit` results while matching a rule, or
+    /// the grammar in `Mode::Structure`. Readers with the same pattern share
+    /// results. Grammars whose alternatives share prefixes, such as nested
+    /// ordered choices, lookaheads and rules called from several places, then
+    /// match each of them once per offset instead of once per branch, at the
+    /// cost of the memory held by the results. Output is the same with or
+    /// without the memo.
+    pub fn with_memo(mut self, limit: usize) -> Lexer {
+        self.memo = Some(limit);
+        self
+    }
+
+    /// What the analysis of the statements the lexer was built from found
+    /// suspicious: alternatives and rules that can never be used. Empty when
+    /// the analysis failed, see `error`.
+    pub fn warnings(&self) -> &[String] {
+        self.analysis.as_deref().unwrap_or_default()
+    }
+
+    /// Why a statement added by a builder after the lexer was created, such
+    /// as a mode, the trivia or a named rule, was rejected by the analysis.
+    /// Every lex of such a lexer fails with this error at offset 0.
+    pub fn error(&self) -> Option<&str> {
+        self.analysis.as_ref().err().map(String::as_str)
+    }
+
+    /// Checks the statements the lexer is built from again, after a builder
+    /// changed them.
+    fn analyse(mut self) -> Lexer {
+        self.analysis = analysis::check(&self.grammar, self.mode);
+        self
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn context(&self) -> vm::Context<'_> {
+        vm::Context {
+            trivia: self
+                .trivia
+                .as_ref()
+                .map(|(program, trivia)| (program.as_slice(), *trivia)),
+            interleave: self.mode == Mode::Structure,
+            ambiguities: None,
+            recover: self.skip.is_some() || self.insert_delimiters,
+            insert_delimiters: self.insert_delimiters,
+            memo: self.memo,
+            rules: &self.rules,
+            trace: None,
+        }
+    }
+
+    pub fn lex<'src>(&self, input: &'src str) -> Result<Vec<Token<'src>>, LexError> {
+        self.tokens(input).collect()
+    }
+
+    /// Lexes as much of `input` as possible, returning the tokens, including
+    /// the `ERROR_TOKEN`s and `MISSING_TOKEN`s of recoveries, along with
+    /// every error met. Without a `Recovery` lexing stops at the first error,
+    /// as `lex` does.
+    pub fn lex_all<'src>(&self, input: &'src str) -> (Vec<Token<'src>>, Vec<LexError>) {
+        let mut tokens = self.tokens(input);
+        let ok = tokens.by_ref().filter_map(Result::ok).collect();
+        (ok, std::mem::take(&mut tokens.errors))
+    }
+
+    /// Lexes `input` like `lex`, also returning every place where maximal
+    /// munch had to break a tie between alternatives or rules that matched
+    /// the same longest text. Ties inside branches that were backtracked out
+    /// of, or rules that were not kept, are left out.
+    pub fn lex_debug<'src>(
+        &self,
+        input: &'src str,
+    ) -> (Result<Vec<Token<'src>>, LexError>, Vec<Ambiguity>) {
+        let ambiguities = RefCell::new(vec![]);
+        let mut tokens = self.tokens(input);
+        tokens.context.ambiguities = Some(&ambiguities);
+        let result = tokens.collect();
+        (result, ambiguities.into_inner())
+    }
+
+    /// Lexes `input` like `lex`, also recording every instruction the VM
+    /// runs along the way. Trivia is skipped without being traced.
+    pub fn lex_traced<'src>(
+        &self,
+        input: &'src str,
+    ) -> (Result<Vec<Token<'src>>, LexError>, trace::Trace) {
+        let trace = RefCell::new(trace::Trace::default());
+        let mut tokens = self.tokens(input);
+        tokens.context.trace = Some(&trace);
+        let result = tokens.collect();
+        (result, trace.into_inner())
+    }
+
+    /// Lists the compiled programs of the lexer, one instruction per line
+    /// after its `pc`: the grammar or the rules of every mode, then the
+    /// trivia rule, the named rules and the rules recovery syncs on. Rules
+    /// are counted from 1, like in `warnings`.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        let mut section = |title: String, program: &[Instruction], depth: usize| {
+            out.push_str(&title);
+            out.push('\n');
+            trace::disassemble(program, depth, &mut out);
+        };
+        for (name, programs) in &self.rule_sets {
+            match self.mode {
+                Mode::Structure => section("grammar:".to_string(), &programs[0], 1),
+                Mode::Tokens(_) => {
+                    section(format!("mode {}:", name), &[], 1);
+                    for (i, program) in programs.iter().enumerate() {
+                        section(format!("  rule {}:", i + 1), program, 2);
+                    }
+                }
+            }
+        }
+        if let Some((program, _)) = &self.trivia {
+            section("trivia:".to_string(), program, 1);
+        }
+        for (name, program) in &self.rules {
+            section(format!("rule {}:", name), program, 1);
+        }
+        if let Some(Skip::Sync(programs)) = &self.skip {
+            for (i, program) in programs.iter().enumerate() {
+                section(format!("sync {}:", i + 1), program, 1);
+            }
+        }
+        out
+    }
+
+    /// Lazily tokenizes `input`. In `Mode::Tokens` each call to `next` only
+    /// runs the rules needed to produce the next token, so large inputs are
+    /// never tokenized up front. The iterator stops after the first error.
+    pub fn tokens<'src>(&self, input: &'src str) -> Tokens<'_, 'src> {
+        Tokens {
+            lexer: self,
+            context: self.context(),
+            input,
+            offset: 0,
+            pending: VecDeque::new(),
+            modes: vec![0],
+            trivia: vec![],
+            error: None,
+            errors: vec![],
+            done: false,
+            indents: vec![0],
+            line: None,
+        }
+    }
+
+    /// Lexes `input` into a lossless concrete syntax tree whose leaves,
+    /// including unnamed text and trivia, concatenate back to `input`.
+    /// Trivia is always kept in the tree, even with `Trivia::Drop`.
+    pub fn cst(&self, input: &str) -> Result<cst::SyntaxNode, LexError> {
+        if let Some(e) = self.error() {
+            return Err(LexError::new(0, e));
+        }
+        let mut context = self.context();
+        if let Some((program, _)) = context.trivia {
+            context.trivia = Some((program, Trivia::Attach));
+        }
+        let mut states = vec![];
+        let mut offset = 0;
+        match self.mode {
+            Mode::Structure => match vm::execute(&self.rule_sets[0].1[0], input, 0, &context) {
+                Some((run, end)) => {
+                    states.extend(run);
+                    offset = end;
+                }
+                None if self.skip.is_some() => {
+                    states.push(error_state(input, 0, input.len()));
+                    offset = input.len();
+                }
+                None => return Err(LexError::new(0, "Input does not match the grammar")),
+            },
+            Mode::Tokens(select) => {
+                let mut modes = vec![0];
+                while offset < input.len() {
+                    let (trivia, next) = vm::skip(&context, input, offset);
+                    if !trivia.is_empty() {
+                        states.push(State::Trivia(trivia));
+                    }
+                    offset = next;
+                    if offset >= input.len() {
+                        break;
+                    }
+                    match self.next_rule(input, offset, select, &mut modes, &context) {
+                        Ok((run, end)) => {
+                            states.extend(run);
+                            offset = end;
+                        }
+                        Err(_) if self.skip.is_some() => {
+                            let end = self.recover(input, offset, &context);
+                            states.push(error_state(input, offset, end));
+                            offset = end;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        let (trivia, offset) = vm::skip(&context, input, offset);
+        if !trivia.is_empty() {
+            states.push(State::Trivia(trivia));
+        }
+        // with a recovery, input the grammar left over is an error like in
+        // `lex_all`, rather than unmatched
+        if offset < input.len() && self.skip.is_some() {
+            states.push(error_state(input, offset, input.len()));
+        }
+        Ok(cst::build(states, input))
+    }
+
+    /// Skips the input at `offset` that no rule matches, following the
+    /// lexer's recovery strategy. Returns the offset after the skipped text.
+    fn recover(&self, input: &str, offset: usize, context: &vm::Context) -> usize {
+        let next = |at: usize| at + input[at..].chars().next().map_or(0, char::len_utf8);
+        match &self.skip {
+            Some(Skip::Sync(programs)) => {
+                let mut at = offset;
+                while at < input.len() {
+                    let matched = programs
+                        .iter()
+                        .find_map(|program| vm::execute(program, input, at, context));
+                    match matched {
+                        Some((_, end)) if end > offset => return end,
+                        _ => at = next(at),
+                    }
+                }
+                at
+            }
+            _ => next(offset),
+        }
+    }
+
+    /// Runs the rules of the lexer mode on top of `modes` at `offset`, then
+    /// applies the mode changes recorded by the winning rule.
+    fn next_rule<'src>(
+        &self,
+        input: &'src str,
+        offset: usize,
+        select: Select,
+        modes: &mut Vec<usize>,
+        context: &vm::Context,
+    ) -> Result<(Vec<State<'src>>, usize), LexError> {
+        let current = modes.last().copied().unwrap_or(0);
+        let (states, end) =
+            self.select_rule(input, offset, select, &self.rule_sets[current].1, context)?;
+        for change in vm::mode_changes(&states) {
+            match change {
+                Some(name) => match self.rule_sets.iter().position(|(n, _)| n == name) {
+                    Some(index) => modes.push(index),
+                    None => return Err(LexError::new(offset, &format!("Unknown mode {}", name))),
+                },
+                None => {
+                    if modes.len() <= 1 {
+                        return Err(LexError::new(offset, "Cannot pop the default mode"));
+                    }
+                    modes.pop();
+                }
+            }
+        }
+        Ok((states, end))
+    }
+
+    fn select_rule<'src>(
+        &self,
+        input: &'src str,
+        offset: usize,
+        select: Select,
+        programs: &[Vec<Instruction>],
+        context: &vm::Context,
+    ) -> Result<(Vec<State<'src>>, usize), LexError> {
+        let mut best: Option<(Vec<State>, usize, Vec<Ambiguity>)> = None;
+        let mut tied = vec![];
+        for (i, program) in programs.iter().enumerate() {
+            // the ties met by a rule are only reported if the rule is kept
+            let ties = RefCell::new(vec![]);
+            let rule_context = vm::Context {
+                ambiguities: context.ambiguities.map(|_| &ties),
+                ..*context
+            };
+            let Some((states, end)) = vm::execute(program, input, offset, &rule_context) else {
+                continue;
+            };
+            // a rule that consumes nothing would never let the lexer advance
+            if end == offset {
+                continue;
+            }
+            match select {
+                Select::First => {
+                    best = Some((states, end, ties.into_inner()));
+                    break;
+                }
+                Select::Longest => match &best {
+                    Some((_, e, _)) if end < *e => {}
+                    Some((_, e, _)) if end == *e => tied.push(i),
+                    _ => {
+                        tied = vec![i];
+                        best = Some((states, end, ties.into_inner()));
+                    }
+                },
+            }
+        }
+        let (states, end, ties) = best.ok_or_else(|| LexError::new(offset, "No rule matches"))?;
+        if let Some(ambiguities) = context.ambiguities {
+            ambiguities.borrow_mut().extend(ties);
+        }
+        if tied.len() > 1 {
+            vm::report(context, offset, end - offset, tied);
+        }
+        Ok((states, end))
+    }
+}
+
+/// Iterator returned by `Lexer::tokens`.
+pub struct Tokens<'l, 'src> {
+    lexer: &'l Lexer,
+    context: vm::Context<'l>,
+    input: &'src str,
+    offset: usize,
+    pending: VecDeque<Token<'src>>,
+    modes: Vec<usize>,
+    trivia: Vec<Token<'src>>,
+    error: Option<LexError>,
+    errors: Vec<LexError>,
+    done: bool,
+    /// Widths of the open indentation levels, innermost last.
+    indents: Vec<usize>,
+    /// Start of the last line whose indentation was measured.
+    line: Option<usize>,
+}
+
+impl Tokens<'_, '_> {
+    /// Every error met so far, including the ones the lexer recovered from.
+    pub fn errors(&self) -> &[LexError] {
+        &self.errors
+    }
+}
+
+impl<'src> Tokens<'_, 'src> {
+    /// Runs one rule, or the whole grammar in `Mode::Structure`, appending
+    /// its tokens to `pending`.
+    fn step(&mut self) {
+        if let Some(e) = self.lexer.error() {
+            let e = LexError::new(0, e);
+            self.done = true;
+            self.errors.push(e.clone());
+            self.error = Some(e);
+            return;
+        }
+        let context = self.context;
+        let step = match self.lexer.mode {
+            Mode::Structure => {
+                self.done = true;
+                vm::execute(&self.lexer.rule_sets[0].1[0], self.input, 0, &context)
+                    .ok_or_else(|| LexError::new(0, "Input does not match the grammar"))
+            }
+            Mode::Tokens(select) => {
+                let (trivia, offset) = vm::skip(&context, self.input, self.offset);
+                self.trivia.extend(trivia);
+                self.offset = offset;
+                if self.offset >= self.input.len() {
+                    if let Some(last) = self.pending.back_mut() {
+                        last.trailing.append(&mut self.trivia);
+                    }
+                    while self.indents.len() > 1 {
+                        self.indents.pop();
+                        self.push_virtual(DEDENT_TOKEN);
+                    }
+                    self.done = true;
+                    return;
+                }
+                if self.lexer.indentation {
+                    self.indent();
+                    if self.done {
+                        return;
+                    }
+                }
+                self.lexer
+                    .next_rule(self.input, self.offset, select, &mut self.modes, &context)
+            }
+        };
+        match step {
+            Ok((states, end)) => {
+                let mut tokens = into_tokens_with(states, self.input, &self.lexer.hooks);
+                recovered_errors(&tokens, &mut self.errors);
+                if let Some(first) = tokens.first_mut() {
+                    self.trivia.append(&mut first.leading);
+                    first.leading = std::mem::take(&mut self.trivia);
+                }
+                self.pending.extend(tokens);
+                self.offset = end;
+                if self.lexer.mode == Mode::Structure {
+                    self.finish(&context);
+                }
+            }
+            Err(e) if self.lexer.skip.is_some() => {
+                let start = match self.lexer.mode {
+                    Mode::Structure => 0,
+                    Mode::Tokens(_) => self.offset,
+                };
+                let end = match self.lexer.mode {
+                    Mode::Structure => self.input.len(),
+                    Mode::Tokens(_) => self.lexer.recover(self.input, start, &context),
+                };
+                let mut token = Token::new(ERROR_TOKEN, &self.input[start..end], start, end);
+                token.leading = std::mem::take(&mut self.trivia);
+                self.errors.push(e);
+                self.pending.push_back(token);
+                self.offset = end;
+            }
+            Err(e) => {
+                self.done = true;
+                self.errors.push(e.clone());
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl Tokens<'_, '_> {
+    /// Skips the trivia after the grammar of `Mode::Structure`, attaching it
+    /// to the last token, and reports any input the grammar left over. With
+    /// a recovery that input becomes an `ERROR_TOKEN`.
+    fn finish(&mut self, context: &vm::Context) {
+        let (mut trivia, offset) = vm::skip(context, self.input, self.offset);
+        if let Some(last) = self.pending.back_mut() {
+            last.trailing.append(&mut trivia);
+        }
+        self.offset = offset;
+        if offset < self.input.len() {
+            let e = LexError::new(offset, "Unexpected input after the grammar");
+            self.errors.push(e.clone());
+            if self.lexer.skip.is_some() {
+                let rest = &self.input[offset..];
+                let end = self.input.len();
+                self.pending
+                    .push_back(Token::new(ERROR_TOKEN, rest, offset, end));
+                self.offset = end;
+            } else {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Measures the indentation of the line when a rule is about to start at
+    /// its first token, see `Lexer::with_indentation`.
+    fn indent(&mut self) {
+        let blank = |c: char| c == ' ' || c == '\t';
+        if self.input[self.offset..]
+            .chars()
+            .next()
+            .is_some_and(|c| blank(c) || c == '\n' || c == '\r')
+        {
+            return;
+        }
+        let line = self.input[..self.offset].rfind('\n').map_or(0, |i| i + 1);
+        if self.line == Some(line) {
+            return;
+        }
+        self.line = Some(line);
+        let prefix = &self.input[line..self.offset];
+        if !prefix.chars().all(blank) {
+            return;
+        }
+        let width = prefix.chars().count();
+        if width > self.indents[self.indents.len() - 1] {
+            self.indents.push(width);
+            self.push_virtual(INDENT_TOKEN);
+            return;
+        }
+        while width < self.indents[self.indents.len() - 1] {
+            self.indents.pop();
+            self.push_virtual(DEDENT_TOKEN);
+        }
+        if width != self.indents[self.indents.len() - 1] {
+            let e = LexError::new(self.offset, "Inconsistent dedent");
+            self.errors.push(e.clone());
+            match self.lexer.skip {
+                // carry on as if the line opened a level of its own
+                Some(_) => {
+                    self.indents.push(width);
+                    self.push_virtual(INDENT_TOKEN);
+                }
+                None => {
+                    self.done = true;
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    fn push_virtual(&mut self, name: &str) {
+        let at = self.offset;
+        self.pending
+            .push_back(Token::new(name, &self.input[at..at], at, at));
+    }
+}
+
+impl<'src> Iterator for Tokens<'_, 'src> {
+    type Item = Result<Token<'src>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the last token is held back until we know whether trailing trivia follows
+        while self.pending.len() < 2 && !self.done {
+            self.step();
+        }
+        match self.pending.pop_front() {
+            Some(token) => Some(Ok(token)),
+            None => self.error.take().map(Err),
+        }
+    }
+}
+
+fn error_state(input: &str, start: usize, end: usize) -> State<'_> {
+    State::List(
+        ERROR_TOKEN.to_string(),
+        vec![State::Text(&input[start..end], start, end)],
+    )
+}
+
+/// Reports the recoveries made inside rules, by `Statement::Delimited`.
+fn recovered_errors(tokens: &[Token], errors: &mut Vec<LexError>) {
+    for token in tokens {
+        match token.name.as_str() {
+            ERROR_TOKEN => errors.push(LexError::new(token.start, "Unexpected input")),
+            MISSING_TOKEN => errors.push(LexError::new(token.start, "Missing delimiter")),
+            _ => recovered_errors(&token.children, errors),
+        }
+    }
+}
+
+/// Replaces the entry named `name` in `entries`, or appends it.
+fn replace<T>(entries: &mut Vec<(String, T)>, name: &str, value: T) {
+    match entries.iter_mut().find(|(n, _)| n == name) {
+        Some((_, existing)) => *existing = value,
+        None => entries.push((name.to_string(), value)),
+    }
+}
+
+fn compile_rules(rules: &[Statement]) -> Vec<Vec<Instruction>> {
+    rules
+        .iter()
+        .map(|rule| {
+            let mut program = Vec::new();
+            vm::compile(rule, &mut program);
+            program
+        })
+        .collect()
 }
@@ -0,0 +1,103 @@
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+
+use super::instruction::{OwnedToken, Token};
+use super::trace::{Step, Trace};
+use super::value::Value;
+
+/// Serializes the fields shared by `Token` and `OwnedToken`. `value` is left
+/// out when there is none, and the token lists when they are empty.
+fn token<S, T>(
+    serializer: S,
+    name: &'static str,
+    fields: (&str, &str, usize, usize, &Option<Value>),
+    children: &[T],
+    leading: &[T],
+    trailing: &[T],
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    let (token_name, text, start, end, value) = fields;
+    let mut state = serializer.serialize_struct(name, 8)?;
+    state.serialize_field("name", token_name)?;
+    state.serialize_field("text", text)?;
+    state.serialize_field("start", &start)?;
+    state.serialize_field("end", &end)?;
+    match value {
+        Some(value) => state.serialize_field("value", value)?,
+        None => state.skip_field("value")?,
+    }
+    for (key, tokens) in [
+        ("children", children),
+        ("leading", leading),
+        ("trailing", trailing),
+    ] {
+        match tokens.is_empty() {
+            true => state.skip_field(key)?,
+            false => state.serialize_field(key, tokens)?,
+        }
+    }
+    state.end()
+}
+
+impl Serialize for Token<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        token(
+            serializer,
+            "Token",
+            (&self.name, self.text, self.start, self.end, &self.value),
+            &self.children,
+            &self.leading,
+            &self.trailing,
+        )
+    }
+}
+
+impl Serialize for OwnedToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        token(
+            serializer,
+            "OwnedToken",
+            (&self.name, &self.text, self.start, self.end, &self.value),
+            &self.children,
+            &self.leading,
+            &self.trailing,
+        )
+    }
+}
+
+/// Values serialize as the plain number, string, boolean or sequence they
+/// hold.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Float(x) => serializer.serialize_f64(*x),
+            Value::Str(s) => serializer.serialize_str(s),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::List(items) => serializer.collect_seq(items),
+        }
+    }
+}
+
+impl Serialize for Step {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Step", 6)?;
+        state.serialize_field("depth", &self.depth)?;
+        state.serialize_field("pc", &self.pc)?;
+        state.serialize_field("offset", &self.offset)?;
+        state.serialize_field("instruction", &self.instruction)?;
+        state.serialize_field("captures", &self.captures)?;
+        state.serialize_field("threads", &self.threads)?;
+        state.end()
+    }
+}
+
+/// A trace serializes as the sequence of its steps.
+impl Serialize for Trace {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(&self.steps)
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt::{self, Write};
+
+use super::instruction::Instruction;
+
+/// One instruction run by the lexer VM, see `Lexer::lex_traced`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Step {
+    /// How deep the program is nested: 0 for a rule, or a grammar in
+    /// `Mode::Structure`, 1 for a program one of its instructions ran, such
+    /// as an alternative of a `Statement::Longest` or a named rule, and so on.
+    pub depth: usize,
+    pub pc: usize,
+    /// Input offset of the thread running the instruction.
+    pub offset: usize,
+    /// The instruction as `Lexer::disassemble` shows it.
+    pub instruction: String,
+    /// Names of the captures the thread has open, outermost first.
+    pub captures: Vec<String>,
+    /// Threads waiting to be resumed if this one fails.
+    pub threads: usize,
+}
+
+/// Every instruction the lexer VM ran while lexing an input, in order.
+/// Printing it lists one step per line.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Trace {
+    pub steps: Vec<Step>,
+    /// Programs currently running, one per nesting level.
+    running: usize,
+}
+
+impl Trace {
+    pub(crate) fn enter(&mut self) {
+        self.running += 1;
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.running -= 1;
+    }
+
+    pub(crate) fn record(&mut self, step: Step) {
+        self.steps.push(Step {
+            depth: self.running.saturating_sub(1),
+            ..step
+        });
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "    pc offset threads  instruction")?;
+        for step in &self.steps {
+            let indent = "  ".repeat(step.depth);
+            write!(
+                f,
+                "{}{:>6} {:>6} {:>7}  {}",
+                indent, step.pc, step.offset, step.threads, step.instruction
+            )?;
+            if !step.captures.is_empty() {
+                write!(f, "  [{}]", step.captures.join(" "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends one line per instruction of `program` to `out`, indented by
+/// `depth` levels. The programs nested in `Longest` and `Precedence`
+/// instructions follow them, one level deeper.
+pub fn disassemble(program: &[Instruction], depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    for (pc, instruction) in program.iter().enumerate() {
+        let _ = writeln!(out, "{}{:>6}  {}", indent, pc, instruction);
+        match instruction {
+            Instruction::Longest(alternatives) => {
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    let _ = writeln!(out, "{}  alternative {}:", indent, i);
+                    disassemble(alternative, depth + 2, out);
+                }
+            }
+            Instruction::Precedence { atom, operators } => {
+                let _ = writeln!(out, "{}  atom:", indent);
+                disassemble(atom, depth + 2, out);
+                for op in operators {
+                    let _ = writeln!(
+                        out,
+                        "{}  operator {} {:?} {}:",
+                        indent, op.name, op.fixity, op.precedence
+                    );
+                    disassemble(&op.program, depth + 2, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
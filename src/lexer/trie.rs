@@ -0,0 +1,76 @@
+/// A set of keywords stored as a trie, so that finding which keyword starts
+/// at a position reads each input character once.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+struct Node {
+    /// Outgoing edges sorted by character.
+    children: Vec<(char, usize)>,
+    terminal: bool,
+}
+
+impl Trie {
+    pub fn new<S: AsRef<str>>(words: &[S]) -> Trie {
+        let mut trie = Trie {
+            nodes: vec![Node::default()],
+        };
+        for word in words {
+            trie.insert(word.as_ref());
+        }
+        trie
+    }
+
+    fn insert(&mut self, word: &str) {
+        let mut node = 0;
+        for c in word.chars() {
+            node = match self.nodes[node]
+                .children
+                .binary_search_by_key(&c, |(k, _)| *k)
+            {
+                Ok(i) => self.nodes[node].children[i].1,
+                Err(i) => {
+                    let next = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[node].children.insert(i, (c, next));
+                    next
+                }
+            };
+        }
+        self.nodes[node].terminal = true;
+    }
+
+    /// Returns the length in bytes of the longest keyword `input` starts with.
+    pub fn longest_match(&self, input: &str) -> Option<usize> {
+        let mut node = 0;
+        let mut longest = self.nodes[0].terminal.then_some(0);
+        for (i, c) in input.char_indices() {
+            let children = &self.nodes[node].children;
+            match children.binary_search_by_key(&c, |(k, _)| *k) {
+                Ok(j) => node = children[j].1,
+                Err(_) => break,
+            }
+            if self.nodes[node].terminal {
+                longest = Some(i + c.len_utf8());
+            }
+        }
+        longest
+    }
+
+    /// The keywords in the trie, in sorted order.
+    pub fn words(&self) -> Vec<String> {
+        let mut words = vec![];
+        let mut stack = vec![(0, String::new())];
+        while let Some((node, word)) = stack.pop() {
+            if self.nodes[node].terminal {
+                words.push(word.clone());
+            }
+            for (c, child) in self.nodes[node].children.iter().rev() {
+                stack.push((*child, format!("{}{}", word, c)));
+            }
+        }
+        words
+    }
+}
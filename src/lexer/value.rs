@@ -0,0 +1,153 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// A typed value converted from the text of a saved token.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+/// A conversion applied to a saved token, see `Statement::SaveWith`.
+/// Conversions start from the token text as a `Value::Str` and run in order;
+/// the token has no value when one of them does not apply.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Transform {
+    /// Parses an `i64`.
+    Int,
+    /// Parses an `f64`.
+    Float,
+    /// Parses `true` or `false`.
+    Bool,
+    /// Turns any value back into a string.
+    Str,
+    /// Removes leading and trailing whitespace.
+    Trim,
+    Lowercase,
+    Uppercase,
+    /// Strips the surrounding quotes of a string literal and resolves its
+    /// backslash escapes.
+    Unescape,
+    /// Collects the values of the token's saved children, or their text
+    /// when they have none.
+    List,
+    /// Runs the hook registered under this name with `Lexer::with_hook`.
+    Custom(String),
+}
+
+/// A user conversion registered on a lexer.
+pub type Hook = Rc<dyn Fn(&Value) -> Option<Value>>;
+
+/// The named hooks of a lexer. Two sets are equal when they hold the same
+/// closures under the same names.
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<(String, Hook)>);
+
+impl Hooks {
+    /// Registers `hook` under `name`, replacing any hook with that name.
+    pub fn insert(&mut self, name: &str, hook: Hook) {
+        match self.0.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing)) => *existing = hook,
+            None => self.0.push((name.to_string(), hook)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Hook> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, hook)| hook)
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(n, _)| n))
+            .finish()
+    }
+}
+
+impl PartialEq for Hooks {
+    fn eq(&self, other: &Hooks) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .all(|((a, f), (b, g))| a == b && Rc::ptr_eq(f, g))
+    }
+}
+
+/// Runs `transforms` over `text`. `children` are the values used by
+/// `Transform::List`.
+pub fn convert(
+    transforms: &[Transform],
+    text: &str,
+    children: &[Value],
+    hooks: &Hooks,
+) -> Option<Value> {
+    let mut value = Value::Str(text.to_string());
+    for transform in transforms {
+        value = match (transform, value) {
+            (Transform::Int, Value::Str(s)) => Value::Int(s.parse().ok()?),
+            (Transform::Float, Value::Str(s)) => Value::Float(s.parse().ok()?),
+            (Transform::Bool, Value::Str(s)) => Value::Bool(s.parse().ok()?),
+            (Transform::Str, value) => Value::Str(value.to_string()),
+            (Transform::Trim, Value::Str(s)) => Value::Str(s.trim().to_string()),
+            (Transform::Lowercase, Value::Str(s)) => Value::Str(s.to_lowercase()),
+            (Transform::Uppercase, Value::Str(s)) => Value::Str(s.to_uppercase()),
+            (Transform::Unescape, Value::Str(s)) => Value::Str(unescape(&s)?),
+            (Transform::List, _) => Value::List(children.to_vec()),
+            (Transform::Custom(name), value) => hooks.get(name)?(&value)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn unescape(literal: &str) -> Option<String> {
+    let inner = match literal.chars().next() {
+        Some(q @ ('"' | '\'')) if literal.len() >= 2 && literal.ends_with(q) => {
+            &literal[1..literal.len() - 1]
+        }
+        _ => literal,
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            other => other,
+        });
+    }
+    Some(out)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
@@ -1,6 +1,15 @@
-use std::collections::VecDeque;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
 
-use super::instruction::{Instruction, State, Statement, Token};
+use super::instruction::{
+    into_tokens, Ambiguity, Fixity, Instruction, Operator, State, Statement, Token,
+};
+use super::trace::{Step, Trace};
+use super::trie::Trie;
+use super::value::Transform;
+use super::{Trivia, ERROR_TOKEN, MISSING_TOKEN};
+use crate::regex::Regex;
 
 #[derive(Clone, Copy, Debug)]
 struct VM {
@@ -9,6 +18,138 @@ struct VM {
     flag: bool,
     push: bool,
     depth: usize,
+    look: usize,
+    sc: usize,
+    mark: Option<usize>,
+    /// Innermost repetition counter, an index into the run's counter frames.
+    counter: Option<usize>,
+}
+
+/// Settings shared by every run of a lexer's programs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Context<'p> {
+    /// Trivia program and whether its matches are kept.
+    pub trivia: Option<(&'p [Instruction], Trivia)>,
+    /// Whether the trivia is skipped before every reader outside lookaheads,
+    /// as in the single run of `Mode::Structure`, rather than only between
+    /// runs.
+    pub interleave: bool,
+    /// Where ties between maximal munch alternatives are reported, if anyone
+    /// is listening.
+    pub ambiguities: Option<&'p RefCell<Vec<Ambiguity>>>,
+    /// Whether the lexer has a `Recovery`, so that `Statement::Delimited` may
+    /// skip a bad `inner`.
+    pub recover: bool,
+    /// Whether `Statement::Delimited` may insert its missing `close`.
+    pub insert_delimiters: bool,
+    /// How many results a run may memoize, if any, see `Shared`.
+    pub memo: Option<usize>,
+    /// Named rules `Instruction::Call` runs.
+    pub rules: &'p [(String, Vec<Instruction>)],
+    /// Where every instruction run is recorded, if anyone is listening.
+    pub trace: Option<&'p RefCell<Trace>>,
+}
+
+/// What a run matched: the states left by its successful thread, the offset
+/// where it stopped and the ties of the maximal munches on its way there.
+#[derive(Debug, Clone)]
+struct Matched<'src> {
+    states: Vec<State<'src>>,
+    end: usize,
+    ties: Vec<Ambiguity>,
+}
+
+/// What the programs run by one `execute` share: the rule calls in progress
+/// and, with a memo, the results of readers, maximal munches and rule calls
+/// by the offset they ran at. Readers are told apart by their pattern, so that
+/// copies of a statement share results, maximal munches by their instruction
+/// and calls by their rule. Every branch, nested program or rule replaying one
+/// of them at the same offset reuses the first result, so the matching done by
+/// a run is bounded by the size of the grammar times the length of the input.
+/// Results that used the seed of a left recursive call still growing are not
+/// kept, and neither are new ones once `limit` results are stored.
+#[derive(Default)]
+struct Shared<'p, 'src> {
+    /// The rule calls in progress, with the largest result they have grown
+    /// so far and whether a recursive call used it.
+    calls: RefCell<HashMap<Call, Seed<'src>>>,
+    /// The results being memoized, innermost last: the rule call each is, if
+    /// it is one, and whether it used the seed of a call further out.
+    frames: RefCell<Vec<(Option<Call>, bool)>>,
+    /// The results of readers, by reader, offset and whether they skipped
+    /// trivia first.
+    reads: RefCell<HashMap<(Reader<'p>, usize, bool), Read<'src>>>,
+    /// The best match of `Longest`s, by instruction address.
+    longest: RefCell<HashMap<(usize, usize), Option<Matched<'src>>>>,
+    called: RefCell<HashMap<Call, Option<Matched<'src>>>>,
+    stored: Cell<usize>,
+    limit: usize,
+}
+
+/// A rule call, by rule and offset.
+type Call = (usize, usize);
+
+/// The seed of a rule call in progress and whether it was used.
+type Seed<'src> = (Option<Matched<'src>>, bool);
+
+/// The trivia a reader skipped, where it started reading and how much it
+/// read.
+type Read<'src> = Option<(Vec<Token<'src>>, usize, usize)>;
+
+/// A reader in the memo: a regex by its pattern or a set of keywords by
+/// address.
+#[derive(PartialEq, Eq, Hash)]
+enum Reader<'p> {
+    Match(&'p str),
+    Keywords(usize),
+}
+
+impl Shared<'_, '_> {
+    fn new(limit: Option<usize>) -> Self {
+        Shared {
+            limit: limit.unwrap_or(0),
+            ..Shared::default()
+        }
+    }
+
+    /// The result stored under `key` in `results`, or else the result of
+    /// `run`, stored if it may be. `call` is the rule call being run, if it
+    /// is one.
+    fn get_or<K: Hash + Eq, T: Clone>(
+        &self,
+        results: &RefCell<HashMap<K, T>>,
+        key: K,
+        call: Option<Call>,
+        run: impl FnOnce() -> T,
+    ) -> T {
+        if self.limit == 0 {
+            return run();
+        }
+        if let Some(result) = results.borrow().get(&key) {
+            return result.clone();
+        }
+        self.frames.borrow_mut().push((call, false));
+        let result = run();
+        let (_, tainted) = self.frames.borrow_mut().pop().unwrap();
+        if !tainted && self.stored.get() < self.limit {
+            self.stored.set(self.stored.get() + 1);
+            results.borrow_mut().insert(key, result.clone());
+        }
+        result
+    }
+
+    /// Marks the results being memoized inside the rule call `call` as using
+    /// its seed.
+    fn taint(&self, call: Call) {
+        let mut frames = self.frames.borrow_mut();
+        let inside = frames
+            .iter()
+            .rposition(|(c, _)| *c == Some(call))
+            .map_or(0, |i| i + 1);
+        for (_, tainted) in &mut frames[inside..] {
+            *tainted = true;
+        }
+    }
 }
 
 pub fn compile(component: &Statement, program: &mut Vec<Instruction>) {
@@ -36,12 +177,63 @@ pub fn compile(component: &Statement, program: &mut Vec<Instruction>) {
                 *pc = end;
             }
         }
+        Statement::Choice(alternatives) => {
+            if alternatives.is_empty() {
+                // nothing to choose from, so it never matches
+                program.push(Instruction::Fail);
+            }
+            let mut jumps = vec![];
+            for (i, alternative) in alternatives.iter().enumerate() {
+                if i + 1 == alternatives.len() {
+                    compile(alternative, program);
+                    break;
+                }
+                let split = program.len();
+                program.push(Instruction::Split(0, 0));
+                compile(alternative, program);
+                jumps.push(program.len());
+                program.push(Instruction::Jmp(0));
+                let next = program.len();
+                if let Instruction::Split(ref mut start, ref mut end) = &mut program[split] {
+                    *start = split + 1;
+                    *end = next;
+                }
+            }
+            let end = program.len();
+            for jmp in jumps {
+                if let Instruction::Jmp(pc) = &mut program[jmp] {
+                    *pc = end;
+                }
+            }
+        }
+        Statement::Keywords(words) => program.push(Instruction::Keywords(Trie::new(words))),
+        Statement::Ordered(alternatives) => {
+            let branches: Vec<_> = alternatives
+                .iter()
+                .map(|alternative| move |p: &mut Vec<Instruction>| compile(alternative, p))
+                .collect();
+            let branches: Vec<Branch> = branches.iter().map(|b| b as Branch).collect();
+            compile_ordered(&branches, program);
+        }
+        Statement::Longest(alternatives) => {
+            let programs = alternatives
+                .iter()
+                .map(|alternative| {
+                    let mut program = Vec::new();
+                    compile(alternative, &mut program);
+                    program
+                })
+                .collect();
+            program.push(Instruction::Longest(programs));
+        }
         Statement::ZeroOrOne(inner) => {
+            let split = program.len();
             program.push(Instruction::Split(0, 0));
             compile(inner, program);
             let end_pos = program.len();
-            if let Instruction::Split(ref mut jmp_offset, _) = &mut program[end_pos - 1] {
-                *jmp_offset = end_pos;
+            if let Instruction::Split(ref mut start, ref mut end) = &mut program[split] {
+                *start = split + 1;
+                *end = end_pos;
             }
         }
         Statement::ZeroOrMore(inner) => {
@@ -66,24 +258,48 @@ pub fn compile(component: &Statement, program: &mut Vec<Instruction>) {
                 *end = end_pos;
             }
         }
-        Statement::Condition { cond, inner } => {
-            let push: usize = program.len();
-            program.push(Instruction::Push(0));
-            compile(cond, program);
-            program.push(Instruction::Pop);
-            let carry = program.len();
-            program.push(Instruction::Carry);
-            let jump_f = program.len();
-            program.push(Instruction::JumpF(0));
+        Statement::Repeat { inner, min, max } => {
+            // a single copy of `inner`, driven by a counter:
+            //
+            //         PushCounter
+            // loop:   Repeat(min, max, exit)
+            //         <inner>
+            //         Increment(loop)
+            // exit:   PopCounter
+            program.push(Instruction::PushCounter);
+            let repeat = program.len();
+            program.push(Instruction::Repeat {
+                min: *min,
+                max: *max,
+                exit: 0,
+            });
             compile(inner, program);
+            program.push(Instruction::Increment(repeat));
             let end = program.len();
-            if let Instruction::Push(addr) = &mut program[push] {
-                *addr = carry;
+            program.push(Instruction::PopCounter);
+            if let Instruction::Repeat { exit, .. } = &mut program[repeat] {
+                *exit = end;
             }
-            if let Instruction::JumpF(addr) = &mut program[jump_f] {
-                *addr = end;
+        }
+        Statement::Condition {
+            cond,
+            then,
+            otherwise,
+        } => {
+            let then = |p: &mut Vec<Instruction>| compile(then, p);
+            match otherwise {
+                Some(otherwise) => {
+                    let otherwise = |p: &mut Vec<Instruction>| compile(otherwise, p);
+                    compile_lookahead(cond, &then, Some(&otherwise), program);
+                }
+                None => compile_lookahead(cond, &then, None, program),
             }
-            program.push(Instruction::UnFlag);
+        }
+        Statement::Not(cond) => {
+            compile_lookahead(cond, &|p| p.push(Instruction::Fail), None, program);
+        }
+        Statement::And(cond) => {
+            compile_lookahead(cond, &|_| {}, Some(&|p| p.push(Instruction::Fail)), program);
         }
         Statement::Save(name, inner) => {
             program.push(Instruction::StartCapture(name.clone()));
@@ -91,140 +307,464 @@ pub fn compile(component: &Statement, program: &mut Vec<Instruction>) {
             program.push(Instruction::EndCapture(name.clone()));
             program.push(Instruction::Save(name.clone()));
         }
+        Statement::SaveWith(name, transforms, inner) => {
+            compile(&Statement::Save(name.clone(), inner.clone()), program);
+            program.push(Instruction::Transform(transforms.clone()));
+        }
+        Statement::SepBy {
+            item,
+            sep,
+            trailing,
+            min,
+        } => {
+            let next = || Statement::Concat(vec![(**sep).clone(), (**item).clone()]);
+            let mut items = vec![(**item).clone()];
+            items.extend((1..(*min).max(1)).map(|_| next()));
+            items.push(Statement::ZeroOrMore(Box::new(next())));
+            if *trailing {
+                items.push(Statement::ZeroOrOne(sep.clone()));
+            }
+            match min {
+                0 => compile(
+                    &Statement::ZeroOrOne(Box::new(Statement::Concat(items))),
+                    program,
+                ),
+                _ => compile(&Statement::Concat(items), program),
+            }
+        }
+        Statement::Delimited { open, inner, close } => {
+            // anything up to the next `close`, one character at a time
+            let skipped = Statement::ZeroOrMore(Box::new(Statement::Concat(vec![
+                Statement::Not(close.clone()),
+                Statement::Reader(Regex::new(".").unwrap()),
+            ])));
+            let skipped = Statement::Save(ERROR_TOKEN.to_string(), Box::new(skipped));
+            compile(open, program);
+            compile_ordered(
+                &[
+                    &|p| {
+                        compile(inner, p);
+                        compile(close, p);
+                    },
+                    // only taken when the lexer recovers from errors
+                    &|p| {
+                        p.push(Instruction::Recover);
+                        compile(&skipped, p);
+                        compile(close, p);
+                    },
+                    // only taken when the lexer may insert missing delimiters
+                    &|p| {
+                        compile(inner, p);
+                        p.push(Instruction::Insert);
+                    },
+                ],
+                program,
+            );
+        }
+        Statement::PushMode(name) => program.push(Instruction::PushMode(name.clone())),
+        Statement::PopMode => program.push(Instruction::PopMode),
+        Statement::Rule(name) => program.push(Instruction::Call(name.clone())),
+        Statement::Precedence { atom, operators } => {
+            let compiled = |statement: &Statement| {
+                let mut program = Vec::new();
+                compile(statement, &mut program);
+                program
+            };
+            program.push(Instruction::Precedence {
+                atom: compiled(atom),
+                operators: operators
+                    .iter()
+                    .map(|op| Operator {
+                        name: op.name.clone(),
+                        program: compiled(&op.op),
+                        fixity: op.fixity,
+                        precedence: op.precedence,
+                    })
+                    .collect(),
+            });
+        }
+    }
+}
+
+/// Emits the instructions of one branch of a lookahead.
+type Branch<'a> = &'a dyn Fn(&mut Vec<Instruction>);
+
+/// Compiles a PEG ordered choice between `alternatives`:
+///
+/// ```text
+///         Choice(next)
+///         <alternative>
+///         Commit(end)
+/// next:   ...
+///         <last alternative>
+/// end:
+/// ```
+fn compile_ordered(alternatives: &[Branch], program: &mut Vec<Instruction>) {
+    if alternatives.is_empty() {
+        program.push(Instruction::Fail);
+    }
+    let mut commits = vec![];
+    for (i, alternative) in alternatives.iter().enumerate() {
+        if i + 1 == alternatives.len() {
+            alternative(program);
+            break;
+        }
+        let choice = program.len();
+        program.push(Instruction::Choice(0));
+        alternative(program);
+        commits.push(program.len());
+        program.push(Instruction::Commit(0));
+        let next = program.len();
+        if let Instruction::Choice(addr) = &mut program[choice] {
+            *addr = next;
+        }
+    }
+    let end = program.len();
+    for commit in commits {
+        if let Instruction::Commit(addr) = &mut program[commit] {
+            *addr = end;
+        }
     }
 }
 
-pub fn execute(program: &[Instruction], input: &str) -> Option<String> {
+/// Compiles `cond` as a lookahead that consumes nothing, followed by `then`
+/// when it matched and `otherwise` when it did not:
+///
+/// ```text
+///         Push(carry)
+///         <cond>
+///         Pop
+/// carry:  Carry
+///         JumpF(else)
+///         <then>
+///         Jmp(end)       ; only with `otherwise`
+/// else:   UnFlag
+///         <otherwise>
+/// end:
+/// ```
+fn compile_lookahead(
+    cond: &Statement,
+    then: Branch,
+    otherwise: Option<Branch>,
+    program: &mut Vec<Instruction>,
+) {
+    let push: usize = program.len();
+    program.push(Instruction::Push(0));
+    compile(cond, program);
+    program.push(Instruction::Pop);
+    let carry = program.len();
+    program.push(Instruction::Carry);
+    let jump_f = program.len();
+    program.push(Instruction::JumpF(0));
+    then(program);
+    let jmp = program.len();
+    if otherwise.is_some() {
+        program.push(Instruction::Jmp(0));
+    }
+    let unflag = program.len();
+    program.push(Instruction::UnFlag);
+    if let Some(otherwise) = otherwise {
+        otherwise(program);
+    }
+    let end = program.len();
+    if let Instruction::Push(addr) = &mut program[push] {
+        *addr = carry;
+    }
+    if let Instruction::JumpF(addr) = &mut program[jump_f] {
+        *addr = unflag;
+    }
+    if let Instruction::Jmp(addr) = &mut program[jmp] {
+        *addr = end;
+    }
+}
+
+/// An entry of the capture log a run appends to. The log is only ever
+/// truncated, so a thread resuming after a failed branch rolls back whatever
+/// that branch recorded. It is folded into `State`s once a thread succeeds.
+#[derive(Debug)]
+enum Entry<'src> {
+    State(State<'src>),
+    EndCapture,
+    Save(String),
+    Transform(Vec<Transform>),
+    /// A tie of a maximal munch the thread went through.
+    Ambiguity(Ambiguity),
+}
+
+/// Runs `program` once from `offset`, returning the capture states left by the
+/// first successful thread and the offset where it stopped. Saved captures are
+/// the named `State::List`s, see `instruction::into_tokens`. Only the ties met
+/// by the successful thread are reported.
+pub fn execute<'p, 'src>(
+    program: &'p [Instruction],
+    input: &'src str,
+    offset: usize,
+    context: &Context<'p>,
+) -> Option<(Vec<State<'src>>, usize)> {
+    let shared = Shared::new(context.memo);
+    let matched = run(program, input, offset, context, &shared)?;
+    if let Some(ambiguities) = context.ambiguities {
+        ambiguities.borrow_mut().extend(matched.ties);
+    }
+    Some((matched.states, matched.end))
+}
+
+/// Runs `program` like `execute`, as part of the run sharing `shared`. Runs
+/// started by its instructions are traced one level deeper.
+fn run<'p, 'src>(
+    program: &'p [Instruction],
+    input: &'src str,
+    offset: usize,
+    context: &Context<'p>,
+    shared: &Shared<'p, 'src>,
+) -> Option<Matched<'src>> {
+    let Some(trace) = context.trace else {
+        return threads(program, input, offset, context, shared);
+    };
+    trace.borrow_mut().enter();
+    let result = threads(program, input, offset, context, shared);
+    trace.borrow_mut().leave();
+    result
+}
+
+/// Runs the threads of `program` until one of them succeeds.
+fn threads<'p, 'src>(
+    program: &'p [Instruction],
+    input: &'src str,
+    offset: usize,
+    context: &Context<'p>,
+    shared: &Shared<'p, 'src>,
+) -> Option<Matched<'src>> {
     let mut stack: VecDeque<VM> = VecDeque::new();
-    let mut state: VecDeque<State> = VecDeque::new();
-    let mut tokens: VecDeque<Token> = VecDeque::new();
-    let input_chars: Vec<char> = input.chars().collect();
+    let mut log: Vec<Entry> = vec![];
     let mut old: Option<VM> = None;
+    // counter frames as (count, enclosing frame); threads share them and
+    // never modify one in place, so forking a thread copies no counters
+    let mut counters: Vec<(usize, Option<usize>)> = vec![];
     stack.push_back(VM {
         pc: 0,
-        tc: 0,
+        tc: offset,
         flag: false,
         push: false,
         depth: 0,
+        look: 0,
+        sc: 0,
+        mark: None,
+        counter: None,
     });
     while let Some(mut vm) = stack.pop_back() {
+        log.truncate(vm.sc);
         let mut opc = vm.pc;
-        println!("{:?} {} {}", vm, program.len(), stack.len());
         loop {
-            if vm.pc >= program.len() || vm.tc > input_chars.len() {
+            if vm.pc >= program.len() && !vm.push {
+                let (states, ties) = fold(log);
+                return Some(Matched {
+                    states,
+                    end: vm.tc,
+                    ties,
+                });
+            }
+            if vm.pc >= program.len() || vm.tc > input.len() {
                 break;
             }
-            println!("{:?}", program[vm.pc]);
+            if let Some(trace) = context.trace {
+                trace.borrow_mut().record(Step {
+                    depth: 0,
+                    pc: vm.pc,
+                    offset: vm.tc,
+                    instruction: program[vm.pc].to_string(),
+                    captures: captures(&log),
+                    threads: stack.len(),
+                });
+            }
             match &program[vm.pc] {
-                Instruction::Match(regex) => {
-                    if let Some(text) = regex.matches(&input[vm.tc..]) {
-                        let len = text.len();
-                        println!("{}", text);
+                Instruction::Match(_) | Instruction::Keywords(_) => {
+                    let reader = match &program[vm.pc] {
+                        Instruction::Match(regex) => Reader::Match(regex.pattern()),
+                        trie => Reader::Keywords(trie as *const Instruction as usize),
+                    };
+                    let interleave = context.interleave && !vm.push;
+                    let key = (reader, vm.tc, interleave);
+                    let read = shared.get_or(&shared.reads, key, None, || {
+                        let (trivia, tc) = if interleave {
+                            skip(context, input, vm.tc)
+                        } else {
+                            (vec![], vm.tc)
+                        };
+                        let matched = match &program[vm.pc] {
+                            Instruction::Match(regex) => regex.match_len(&input[tc..]),
+                            Instruction::Keywords(trie) => trie.longest_match(&input[tc..]),
+                            _ => None,
+                        };
+                        matched.map(|len| (trivia, tc, len))
+                    });
+                    if let Some((trivia, tc, len)) = read {
+                        let text = &input[tc..tc + len];
                         if !vm.push {
-                            state.push_back(State::Text(text, vm.tc, vm.tc + len));
+                            if !trivia.is_empty() {
+                                log.push(Entry::State(State::Trivia(trivia)));
+                            }
+                            log.push(Entry::State(State::Text(text, tc, tc + len)));
                         }
                         vm.pc += 1;
-                        vm.tc += len;
-                    } else if vm.push {
-                        vm.flag = true;
-                        vm.pc += 1;
+                        vm.tc = tc + len;
+                    } else {
+                        break;
                     }
                 }
                 Instruction::Split(a, b) => {
                     stack.push_back(VM {
                         pc: *b,
-                        tc: vm.tc,
-                        flag: vm.flag,
-                        push: false,
-                        depth: vm.depth,
+                        sc: log.len(),
+                        ..vm
+                    });
+                    vm.pc = *a;
+                }
+                Instruction::Choice(a) => {
+                    stack.push_back(VM {
+                        pc: *a,
+                        sc: log.len(),
+                        ..vm
                     });
+                    vm.mark = Some(stack.len() - 1);
+                    vm.pc += 1;
+                }
+                Instruction::Commit(a) => {
+                    // drop the remaining alternatives of the choice and every
+                    // thread the taken alternative left behind
+                    if let Some(mark) = vm.mark {
+                        vm.mark = stack.get(mark).and_then(|t| t.mark);
+                        stack.truncate(mark);
+                    }
                     vm.pc = *a;
                 }
+                Instruction::Longest(alternatives) => {
+                    let at = &program[vm.pc] as *const Instruction as usize;
+                    let best = shared.get_or(&shared.longest, (at, vm.tc), None, || {
+                        let mut best: Option<Matched> = None;
+                        let mut tied = vec![];
+                        for (i, alternative) in alternatives.iter().enumerate() {
+                            let Some(matched) = run(alternative, input, vm.tc, context, shared)
+                            else {
+                                continue;
+                            };
+                            match &best {
+                                Some(b) if matched.end < b.end => {}
+                                Some(b) if matched.end == b.end => tied.push(i),
+                                _ => {
+                                    tied = vec![i];
+                                    best = Some(matched);
+                                }
+                            }
+                        }
+                        if let Some(best) = &mut best {
+                            if tied.len() > 1 {
+                                best.ties.push(Ambiguity {
+                                    offset: vm.tc,
+                                    len: best.end - vm.tc,
+                                    alternatives: tied,
+                                });
+                            }
+                        }
+                        best
+                    });
+                    let Some(best) = best else {
+                        break;
+                    };
+                    vm.tc = best.end;
+                    if !vm.push {
+                        append(&mut log, best);
+                    }
+                    vm.pc += 1;
+                }
                 Instruction::Jmp(a) => {
                     vm.pc = *a;
                 }
+                Instruction::Precedence { atom, operators } => {
+                    let expression = Expression {
+                        atom,
+                        operators,
+                        input,
+                        context,
+                        shared,
+                    };
+                    let Some(matched) = expression.operand(vm.tc, None) else {
+                        break;
+                    };
+                    vm.tc = matched.end;
+                    if !vm.push {
+                        append(&mut log, matched);
+                    }
+                    vm.pc += 1;
+                }
+                Instruction::Call(name) => {
+                    let Some(rule) = context.rules.iter().position(|(n, _)| n == name) else {
+                        break;
+                    };
+                    let Some(matched) = call(rule, input, vm.tc, context, shared) else {
+                        break;
+                    };
+                    vm.tc = matched.end;
+                    if !vm.push {
+                        append(&mut log, matched);
+                    }
+                    vm.pc += 1;
+                }
+                Instruction::PushCounter => {
+                    counters.push((0, vm.counter));
+                    vm.counter = Some(counters.len() - 1);
+                    vm.pc += 1;
+                }
+                Instruction::Repeat { min, max, exit } => {
+                    let count = vm.counter.map_or(0, |c| counters[c].0);
+                    if max.is_some_and(|max| count >= max) {
+                        vm.pc = *exit;
+                    } else {
+                        if count >= *min {
+                            stack.push_back(VM {
+                                pc: *exit,
+                                sc: log.len(),
+                                ..vm
+                            });
+                        }
+                        vm.pc += 1;
+                    }
+                }
+                Instruction::Increment(a) => {
+                    if let Some(c) = vm.counter {
+                        counters.push((counters[c].0 + 1, counters[c].1));
+                        vm.counter = Some(counters.len() - 1);
+                    }
+                    vm.pc = *a;
+                }
+                Instruction::PopCounter => {
+                    vm.counter = vm.counter.and_then(|c| counters[c].1);
+                    vm.pc += 1;
+                }
                 Instruction::JumpF(a) => {
                     vm.pc += 1;
                     if vm.flag {
                         vm.pc = *a
                     }
                 }
+                Instruction::Save(_) if vm.push => vm.pc += 1,
                 Instruction::Save(name) => {
-                    let s = state.back_mut();
-                    if let Some(m) = s {
-                        match m {
-                            State::StartCapture(_, _) => panic!("ERROR Invalid state"),
-                            State::Text(text, start, end) => {
-                                if vm.depth == 0 {
-                                    tokens.push_back(Token {
-                                        name: name.clone(),
-                                        text: text.clone(),
-                                        start: start.clone(),
-                                        end: end.clone(),
-                                        children: vec![],
-                                    });
-                                    state.pop_back();
-                                }
-                            }
-                            State::List(n, _) => {
-                                if vm.depth == 0 {
-                                    if let Some(mut t) = m.clone().into_token() {
-                                        t.name = name.to_string();
-                                        tokens.push_back(t);
-                                    }
-                                    state.pop_back();
-                                } else {
-                                    *n = name.clone();
-                                }
-                            }
-                        }
-                    }
+                    log.push(Entry::Save(name.clone()));
                     vm.pc += 1;
                 }
+                Instruction::Transform(_) if vm.push => vm.pc += 1,
+                Instruction::Transform(transforms) => {
+                    log.push(Entry::Transform(transforms.clone()));
+                    vm.pc += 1;
+                }
+                Instruction::StartCapture(_) | Instruction::EndCapture(_) if vm.push => vm.pc += 1,
                 Instruction::StartCapture(a) => {
                     vm.depth += 1;
-                    state.push_back(State::StartCapture(a.clone(), vm.tc));
+                    log.push(Entry::State(State::StartCapture(a.clone(), vm.tc)));
                     vm.pc += 1;
                 }
-                Instruction::EndCapture(a) => {
-                    println!("{:?}", state);
-                    let mut i = state.len();
-                    let mut capture: Option<State> = None;
-                    while i > 0 {
-                        i -= 1;
-                        if let Some(State::StartCapture(_, _)) = state.get(i) {
-                            capture = state.get(i).cloned();
-                            break;
-                        }
-                    }
-                    if let Some(State::StartCapture(_, _)) = capture {
-                        let mut list: Vec<State> = vec![];
-                        while state.len() > i + 1 {
-                            if let Some(t) = state.pop_back() {
-                                println!("out: {:?}", t);
-                                list.push(t);
-                            } else {
-                                panic!("error");
-                                // error
-                            }
-                        }
-                        list.reverse();
-                        state.pop_back();
-                        state.push_back(State::List("".to_string(), list));
-                        // let mut text = String::new();
-                        // while state.len() > i + 1 {
-                        //     if let Some(State::Text(t, _, _)) = state.pop_back() {
-                        //         text = t + text.as_str();
-                        //         println!("out: {}", text);
-                        //     } else {
-                        //         panic!("error");
-                        //         // error
-                        //     }
-                        // }
-                        // state.pop_back();
-                        // let len = 0;
-                        // state.push_back(State::Text(text, start, start + len));
-                    }
+                Instruction::EndCapture(_) => {
+                    log.push(Entry::EndCapture);
                     vm.depth -= 1;
                     vm.pc += 1;
                 }
@@ -237,34 +777,60 @@ pub fn execute(program: &[Instruction], input: &str) -> Option<String> {
                     vm.flag = false
                 }
                 Instruction::Carry => {
-                    if let Some(ovm) = old {
-                        vm.flag = ovm.flag
-                    } else {
-                        panic!("ERROR 1111")
-                    }
+                    // `old` is only set when the lookahead reached its `Pop`
+                    vm.flag = old.take().is_none();
                     vm.pc += 1;
                 }
                 Instruction::Push(v) => {
-                    vm.pc += 1;
+                    old = None;
                     stack.push_back(VM {
                         pc: *v,
-                        tc: vm.tc,
-                        flag: vm.flag,
-                        push: false,
-                        depth: vm.depth,
-                    });
-                    stack.push_back(VM {
-                        pc: vm.pc,
-                        tc: vm.tc,
-                        flag: vm.flag,
-                        push: true,
-                        depth: vm.depth,
+                        sc: log.len(),
+                        ..vm
                     });
-                    break;
+                    vm.pc += 1;
+                    vm.push = true;
+                    vm.look += 1;
+                }
+                Instruction::Fail => break,
+                Instruction::Recover => {
+                    if !context.recover {
+                        break;
+                    }
+                    vm.pc += 1;
+                }
+                Instruction::Insert => {
+                    if !context.insert_delimiters {
+                        break;
+                    }
+                    if !vm.push {
+                        let missing = State::Text(&input[vm.tc..vm.tc], vm.tc, vm.tc);
+                        log.push(Entry::State(State::List(
+                            MISSING_TOKEN.to_string(),
+                            vec![missing],
+                        )));
+                    }
+                    vm.pc += 1;
+                }
+                Instruction::PushMode(name) => {
+                    if !vm.push {
+                        log.push(Entry::State(State::PushMode(name.clone())));
+                    }
+                    vm.pc += 1;
+                }
+                Instruction::PopMode => {
+                    if !vm.push {
+                        log.push(Entry::State(State::PopMode));
+                    }
+                    vm.pc += 1;
                 }
                 Instruction::Pop => {
+                    // the lookahead matched, so its other alternatives are
+                    // dropped and its continuation runs next
+                    while stack.back().is_some_and(|t| t.look >= vm.look) {
+                        stack.pop_back();
+                    }
                     old = Some(vm);
-                    vm.pc += 1;
                     break;
                 }
             }
@@ -276,6 +842,243 @@ pub fn execute(program: &[Instruction], input: &str) -> Option<String> {
             opc = vm.pc;
         }
     }
-    println!("{:?}", tokens);
-    return None;
+    None
+}
+
+/// Appends what a nested run matched to `log`.
+fn append<'src>(log: &mut Vec<Entry<'src>>, matched: Matched<'src>) {
+    log.extend(matched.states.into_iter().map(Entry::State));
+    log.extend(matched.ties.into_iter().map(Entry::Ambiguity));
+}
+
+/// Names of the captures open at the end of `log`, outermost first.
+fn captures(log: &[Entry]) -> Vec<String> {
+    let mut open = vec![];
+    for entry in log {
+        match entry {
+            Entry::State(State::StartCapture(name, _)) => open.push(name.clone()),
+            Entry::EndCapture => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    open
+}
+
+/// Runs the rule `rule` at `offset`. A rule matches as a whole: what follows
+/// it never backtracks into it for another match.
+///
+/// Left recursion is grown from a seed (Warth et al., "Packrat Parsers Can
+/// Support Left Recursion"): while a call is in progress, a call of the same
+/// rule at the same offset, directly or through other rules, does not recurse
+/// but returns the result grown so far, failing at first. Whenever that
+/// result was used the rule runs again, until its match stops getting
+/// longer. Each round nests the previous match inside the new one, so
+/// `expr := expr "+" term | term` reads `1+2+3` as `(1+2)+3`.
+fn call<'p, 'src>(
+    rule: usize,
+    input: &'src str,
+    offset: usize,
+    context: &Context<'p>,
+    shared: &Shared<'p, 'src>,
+) -> Option<Matched<'src>> {
+    let key = (rule, offset);
+    if let Some((seed, used)) = shared.calls.borrow_mut().get_mut(&key) {
+        *used = true;
+        shared.taint(key);
+        return seed.clone();
+    }
+    shared.get_or(&shared.called, key, Some(key), || {
+        shared.calls.borrow_mut().insert(key, (None, false));
+        loop {
+            let result = run(&context.rules[rule].1, input, offset, context, shared);
+            let mut calls = shared.calls.borrow_mut();
+            let (seed, used) = calls.get_mut(&key).unwrap();
+            let grown = match (&result, &seed) {
+                (Some(result), Some(last)) => result.end > last.end,
+                (result, _) => result.is_some(),
+            };
+            if grown && *used {
+                *seed = result;
+                *used = false;
+                continue;
+            }
+            let (seed, _) = calls.remove(&key).unwrap();
+            return if grown { result } else { seed };
+        }
+    })
+}
+
+/// A `Statement::Precedence` being matched by precedence climbing.
+struct Expression<'a, 'p, 'src> {
+    atom: &'p [Instruction],
+    operators: &'p [Operator],
+    input: &'src str,
+    context: &'a Context<'p>,
+    shared: &'a Shared<'p, 'src>,
+}
+
+impl<'p, 'src> Expression<'_, 'p, 'src> {
+    /// Matches a prefix operator and its operand, or else an atom, at
+    /// `offset`, then as many postfix and infix operators as follow that
+    /// bind tighter than `above`, or any operator when it is `None`. The
+    /// right operand of an infix operator only takes operators binding
+    /// tighter than it, or as tight when it is right associative. An
+    /// operator whose operand does not match is left for the caller, and the
+    /// first operator in order that fits is used.
+    fn operand(&self, offset: usize, above: Option<usize>) -> Option<Matched<'src>> {
+        let mut lhs = None;
+        for op in self.operators {
+            if op.fixity != Fixity::Prefix {
+                continue;
+            }
+            let Some(mut matched) = self.run(&op.program, offset) else {
+                continue;
+            };
+            if let Some(operand) = self.operand(matched.end, op.precedence.checked_sub(1)) {
+                matched.states.extend(operand.states);
+                matched.ties.extend(operand.ties);
+                lhs = Some(Matched {
+                    states: vec![State::List(op.name.clone(), matched.states)],
+                    end: operand.end,
+                    ties: matched.ties,
+                });
+                break;
+            }
+        }
+        let mut lhs = match lhs {
+            Some(lhs) => lhs,
+            None => self.run(self.atom, offset)?,
+        };
+        'apply: loop {
+            for op in self.operators {
+                if op.fixity == Fixity::Prefix || above.is_some_and(|p| op.precedence <= p) {
+                    continue;
+                }
+                let Some(matched) = self.run(&op.program, lhs.end) else {
+                    continue;
+                };
+                let rhs = match op.fixity {
+                    Fixity::InfixLeft => self.operand(matched.end, Some(op.precedence)),
+                    Fixity::InfixRight => self.operand(matched.end, op.precedence.checked_sub(1)),
+                    _ => Some(Matched {
+                        states: vec![],
+                        end: matched.end,
+                        ties: vec![],
+                    }),
+                };
+                let Some(rhs) = rhs else {
+                    continue;
+                };
+                let mut children = std::mem::take(&mut lhs.states);
+                children.extend(matched.states);
+                children.extend(rhs.states);
+                lhs.states = vec![State::List(op.name.clone(), children)];
+                lhs.ties.extend(matched.ties);
+                lhs.ties.extend(rhs.ties);
+                lhs.end = rhs.end;
+                continue 'apply;
+            }
+            return Some(lhs);
+        }
+    }
+
+    fn run(&self, program: &'p [Instruction], offset: usize) -> Option<Matched<'src>> {
+        run(program, self.input, offset, self.context, self.shared)
+    }
+}
+
+/// Records an ambiguous maximal munch if the context collects them.
+pub fn report(context: &Context, offset: usize, len: usize, alternatives: Vec<usize>) {
+    if let Some(ambiguities) = context.ambiguities {
+        ambiguities.borrow_mut().push(Ambiguity {
+            offset,
+            len,
+            alternatives,
+        });
+    }
+}
+
+/// Folds a capture log into states: every `StartCapture` .. `EndCapture`
+/// span becomes a `State::List`, named by the `Save` that follows it. The
+/// ties it recorded are returned alongside.
+fn fold(log: Vec<Entry>) -> (Vec<State>, Vec<Ambiguity>) {
+    let mut states = Vec::with_capacity(log.len());
+    let mut ties = vec![];
+    // the states of the enclosing captures, innermost last
+    let mut frames: Vec<Vec<State>> = vec![];
+    for entry in log {
+        match entry {
+            Entry::State(State::StartCapture(_, _)) => frames.push(std::mem::take(&mut states)),
+            Entry::State(state) => states.push(state),
+            Entry::EndCapture => {
+                if let Some(outer) = frames.pop() {
+                    let list = std::mem::replace(&mut states, outer);
+                    states.push(State::List("".to_string(), list));
+                }
+            }
+            Entry::Save(name) => match states.pop() {
+                Some(State::List(_, list)) => states.push(State::List(name, list)),
+                Some(text @ State::Text(_, _, _)) => states.push(State::List(name, vec![text])),
+                Some(_) => panic!("ERROR Invalid state"),
+                None => {}
+            },
+            Entry::Transform(transforms) => {
+                if let Some(State::List(_, list)) = states.last_mut() {
+                    list.push(State::Transform(transforms));
+                }
+            }
+            Entry::Ambiguity(tie) => ties.push(tie),
+        }
+    }
+    while let Some(mut outer) = frames.pop() {
+        outer.append(&mut states);
+        states = outer;
+    }
+    (states, ties)
+}
+
+/// Skips the context's trivia starting at `offset` as many times as it
+/// matches. Returns the kept trivia tokens and the offset after them.
+pub fn skip<'src>(context: &Context, input: &'src str, offset: usize) -> (Vec<Token<'src>>, usize) {
+    let mut trivia = vec![];
+    let mut tc = offset;
+    let Some((program, mode)) = context.trivia else {
+        return (trivia, tc);
+    };
+    let trivia_context = Context {
+        rules: context.rules,
+        ..Context::default()
+    };
+    while let Some((states, end)) = execute(program, input, tc, &trivia_context) {
+        if end == tc {
+            break;
+        }
+        if mode == Trivia::Attach {
+            let saved = into_tokens(states, input);
+            if saved.is_empty() {
+                trivia.push(Token::new("", &input[tc..end], tc, end));
+            } else {
+                trivia.extend(saved);
+            }
+        }
+        tc = end;
+    }
+    (trivia, tc)
+}
+
+/// Lists the lexer mode changes recorded in `states`, in order. `Some(name)`
+/// pushes the named mode and `None` pops the current one.
+pub fn mode_changes<'a>(states: &'a [State]) -> Vec<Option<&'a str>> {
+    let mut changes = vec![];
+    for state in states {
+        match state {
+            State::PushMode(name) => changes.push(Some(name.as_str())),
+            State::PopMode => changes.push(None),
+            State::List(_, list) => changes.extend(mode_changes(list)),
+            _ => {}
+        }
+    }
+    changes
 }
@@ -1,19 +1,13 @@
-extern crate cfg_if;
 extern crate wasm_bindgen;
 
 pub mod lexer;
 pub mod regex;
-
-use cfg_if::cfg_if;
-
-cfg_if! {
-    if #[cfg(feature = "wee_alloc")] {
-        extern crate wee_alloc;
-        #[global_allocator]
-        static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
-    }
-}
-
-pub use lexer::instruction::Statement;
-pub use lexer::Lexer;
+pub mod snapshot;
+
+#[cfg(feature = "serde")]
+pub use lexer::de::from_tokens;
+pub use lexer::dump::DumpOptions;
+pub use lexer::instruction::{Fixity, OpDef, Statement};
+pub use lexer::value::{Transform, Value};
+pub use lexer::{Lexer, Mode, Recovery, Select, Trivia};
 pub use regex::Regex;
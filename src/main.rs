@@ -1,4 +1,5 @@
-use pulp::{lexer, Lexer, Regex, Statement};
+use pulp::lexer::builder::re;
+use pulp::Lexer;
 
 fn main() {
     // let input = "aaabcbceebc";
@@ -7,32 +8,18 @@ fn main() {
     //     println!("Input matches pattern: {}", matches);
     // }
     // let lexer = Lexer::new(Statement::);
-    let lexer = Lexer::new(Statement::Save(
-        "ident".to_string(),
-        Box::new(Statement::Concat(
-            vec![
-                Statement::Condition {
-                    cond: Box::new(Statement::Reader(Regex::new(r"@").unwrap())),
-                    inner: Box::new(Statement::Concat(vec![
-                        Statement::Reader(Regex::new(r"@").unwrap()),
-                        Statement::Reader(Regex::new(r"\w+").unwrap()),
-                        Statement::Reader(Regex::new(r"\n").unwrap()),
-                    ])),
-                },
-                Statement::Reader(Regex::new(r"lexer").unwrap()),
-                Statement::Save(
-                    "block".to_string(),
-                    Box::new(Statement::Concat(vec![
-                        Statement::Reader(Regex::new(r"\s*{").unwrap()),
-                        Statement::Reader(Regex::new(r"\s*}").unwrap()),
-                    ])),
-                ),
-            ], // Box::new(Statement::Reader(Regex::new(r"\w+").unwrap())),
-        )),
-    ));
+    let lexer = Lexer::new(
+        re(r"@")
+            .then(re(r"\w+"))
+            .then(re(r"\n"))
+            .when(re(r"@"))
+            .then(re(r"lexer"))
+            .then((re(r"\s*{") + re(r"\s*}")).save("block"))
+            .save("ident"),
+    );
 
     if let Ok(lexer) = lexer {
-        lexer.lex("@merge");
+        println!("{:?}", lexer.lex("@merge"));
     }
     // if let Ok(lexer) = lexer {
     //     lexer.lex("aaabcbcee bc");
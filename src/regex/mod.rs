@@ -1,6 +1,8 @@
 pub mod instruction;
-pub mod vm;
 pub mod parser;
+pub mod vm;
+use std::fmt;
+
 use instruction::Instruction;
 use wasm_bindgen::prelude::wasm_bindgen;
 
@@ -8,6 +10,7 @@ use wasm_bindgen::prelude::wasm_bindgen;
 #[derive(Debug, PartialEq, Clone)]
 pub struct Regex {
     program: Vec<Instruction>,
+    pattern: String,
 }
 
 #[wasm_bindgen]
@@ -17,10 +20,86 @@ impl Regex {
         let mut program = Vec::new();
         vm::compile(&expr, &mut program);
         program.push(Instruction::Match);
-        Ok(Regex { program })
+        Ok(Regex {
+            program,
+            pattern: regex.to_string(),
+        })
     }
 
     pub fn matches(&self, input: &str) -> Option<String> {
+        self.match_len(input).map(|len| input[..len].to_string())
+    }
+
+    /// Returns the length in bytes of the match anchored at the start of
+    /// `input`, without copying the matched text.
+    pub fn match_len(&self, input: &str) -> Option<usize> {
         vm::execute(&self.program, input)
     }
 }
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl Regex {
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the regex matches empty input, and so matches everywhere.
+    pub fn nullable(&self) -> bool {
+        self.closure(0)
+            .into_iter()
+            .any(|pc| self.program[pc] == Instruction::Match)
+    }
+
+    /// The instructions that can read the first character of a match, with
+    /// every `Text` narrowed to its first `Char`, and whether that character
+    /// alone decides the match: whether the regex can stop after any of them.
+    pub fn first(&self) -> (Vec<Instruction>, bool) {
+        let mut first = vec![];
+        let mut decided = true;
+        let stops = |pc: usize| {
+            self.closure(pc)
+                .into_iter()
+                .any(|pc| self.program[pc] == Instruction::Match)
+        };
+        for pc in self.closure(0) {
+            match &self.program[pc] {
+                Instruction::Match => {}
+                Instruction::Text(text) => {
+                    first.push(Instruction::Char(text[0]));
+                    decided &= text.len() == 1 && stops(pc + 1);
+                }
+                class => {
+                    first.push(class.clone());
+                    decided &= stops(pc + 1);
+                }
+            }
+        }
+        (first, decided)
+    }
+
+    /// The instructions other than jumps reachable from `pc` without reading
+    /// any input.
+    fn closure(&self, pc: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.program.len()];
+        let mut stack = vec![pc];
+        let mut reached = vec![];
+        while let Some(pc) = stack.pop() {
+            if pc >= self.program.len() || seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+            match &self.program[pc] {
+                Instruction::Jmp(a) => stack.push(*a),
+                Instruction::Split(a, b) => stack.extend([*b, *a]),
+                Instruction::Text(text) if text.is_empty() => stack.push(pc + 1),
+                _ => reached.push(pc),
+            }
+        }
+        reached
+    }
+}
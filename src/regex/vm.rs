@@ -73,101 +73,57 @@ pub fn compile(expr: &Expr, program: &mut Vec<Instruction>) {
     }
 }
 
-pub fn execute(program: &[Instruction], input: &str) -> Option<String> {
+/// Runs `program` anchored at the start of `input` and returns the length in
+/// bytes of the first match. The input is decoded lazily, so only the matched
+/// prefix is ever read.
+pub fn execute(program: &[Instruction], input: &str) -> Option<usize> {
     let mut stack: VecDeque<VM> = VecDeque::new();
-    let input_chars: Vec<char> = input.chars().collect();
     stack.push_back(VM { pc: 0, tc: 0 });
     while let Some(mut vm) = stack.pop_back() {
         loop {
-            if vm.pc >= program.len() || vm.tc > input_chars.len() {
+            if vm.pc >= program.len() || vm.tc > input.len() {
                 return None;
             }
-            match &program[vm.pc] {
-                Instruction::Any => {
-                    if vm.tc >= input.len() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                }
-                Instruction::AnyWhitespace => {
-                    if vm.tc >= input.len() || !input_chars[vm.tc].is_whitespace() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                },
-                Instruction::AnyNonWhitespace => {
-                    if vm.tc >= input.len() || input_chars[vm.tc].is_whitespace() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                },
-                Instruction::AnyDigit => {
-                    if vm.tc >= input.len() || !input_chars[vm.tc].is_numeric() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                }
-                Instruction::AnyNonDigit => {
-                    if vm.tc >= input.len() || input_chars[vm.tc].is_numeric() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                },
-                Instruction::AnyWord =>  {
-                    if vm.tc >= input.len() || !input_chars[vm.tc].is_alphabetic() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                },
-                Instruction::AnyNonWord => {
-                    if vm.tc >= input.len() || input_chars[vm.tc].is_alphabetic() {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                },
-                Instruction::Char(c) => {
-                    if vm.tc >= input.len() || input_chars[vm.tc] != *c {
-                        break;
-                    }
-                    vm.pc += 1;
-                    vm.tc += 1;
-                }
+            let next = input[vm.tc..].chars().next();
+            let class = |test: fn(&char) -> bool| next.filter(test).map(char::len_utf8);
+            let step = match &program[vm.pc] {
+                Instruction::Any => next.map(char::len_utf8),
+                Instruction::AnyWhitespace => class(|c| c.is_whitespace()),
+                Instruction::AnyNonWhitespace => class(|c| !c.is_whitespace()),
+                Instruction::AnyDigit => class(|c| c.is_numeric()),
+                Instruction::AnyNonDigit => class(|c| !c.is_numeric()),
+                Instruction::AnyWord => class(|c| c.is_alphabetic()),
+                Instruction::AnyNonWord => class(|c| !c.is_alphabetic()),
+                Instruction::Char(c) => next.filter(|n| n == c).map(char::len_utf8),
                 Instruction::Text(text) => {
-                    if vm.tc >= input.len() {
-                        break;
-                    }
-                    let mut matched = true;
-                    for n in 0..text.len() {
-                        if vm.tc + n >= input_chars.len() || text[n] != input_chars[vm.tc + n] {
-                            matched = false;
-                            break;
-                        }
-                    }
-                    if !matched {
-                        break;
+                    let mut chars = input[vm.tc..].chars();
+                    if text.iter().all(|c| chars.next() == Some(*c)) {
+                        Some(text.iter().map(|c| c.len_utf8()).sum())
+                    } else {
+                        None
                     }
-                    vm.pc += 1;
-                    vm.tc += text.len();
                 }
                 Instruction::Match => {
-                    return Some(input[0..vm.tc].to_string());
+                    return Some(vm.tc);
                 }
                 Instruction::Jmp(a) => {
                     vm.pc = *a;
+                    continue;
                 }
                 Instruction::Split(a, b) => {
                     stack.push_back(VM { pc: *b, tc: vm.tc });
                     vm.pc = *a;
+                    continue;
+                }
+            };
+            match step {
+                Some(len) => {
+                    vm.pc += 1;
+                    vm.tc += len;
                 }
+                None => break,
             }
         }
     }
-    return None;
+    None
 }
@@ -0,0 +1,126 @@
+//! Golden-file tests for grammars. Every input file in a directory is lexed
+//! and the dump of its tokens is compared with the file of the same name
+//! plus `.expected`. Running with `PULP_BLESS=1` writes the dumps as the new
+//! expected files instead.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::lexer::dump::DumpOptions;
+use crate::Lexer;
+
+/// Environment variable that turns `assert_snapshots` into an update of the
+/// expected files.
+pub const BLESS_VAR: &str = "PULP_BLESS";
+
+const EXPECTED: &str = "expected";
+
+/// Dumps the tokens `lexer` produces for `input` as S-expressions, one tree
+/// after another, followed by the error that stopped the lexer if any.
+pub fn dump(lexer: &Lexer, input: &str, options: DumpOptions) -> String {
+    let mut out = String::new();
+    for token in lexer.tokens(input) {
+        match token {
+            Ok(token) => out.push_str(&token.to_sexpr(options)),
+            Err(e) => out.push_str(&format!("error: {}", e)),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Checks every input file in `dir` against its `.expected` file, or
+/// rewrites the expected files when `PULP_BLESS` is set.
+///
+/// # Panics
+///
+/// Panics listing every input whose dump is missing or differs, with the
+/// first line that differs.
+pub fn assert_snapshots(lexer: &Lexer, dir: impl AsRef<Path>) {
+    let bless = std::env::var_os(BLESS_VAR).is_some_and(|v| !v.is_empty() && v != "0");
+    if let Err(failures) = check_snapshots(lexer, dir.as_ref(), bless) {
+        panic!(
+            "{} snapshot(s) differ, rerun with {}=1 to update them:\n{}",
+            failures.len(),
+            BLESS_VAR,
+            failures.join("\n")
+        );
+    }
+}
+
+/// Compares or, with `bless`, rewrites the snapshots in `dir`. Returns a
+/// description of each failing input.
+pub fn check_snapshots(lexer: &Lexer, dir: &Path, bless: bool) -> Result<(), Vec<String>> {
+    let mut failures = vec![];
+    for input in inputs(dir).map_err(|e| vec![format!("{}: {}", dir.display(), e)])? {
+        let expected = input.with_extension(match input.extension() {
+            Some(ext) => format!("{}.{}", ext.to_string_lossy(), EXPECTED),
+            None => EXPECTED.to_string(),
+        });
+        let text = match fs::read_to_string(&input) {
+            Ok(text) => text,
+            Err(e) => {
+                failures.push(format!("{}: {}", input.display(), e));
+                continue;
+            }
+        };
+        let actual = dump(lexer, &text, DumpOptions::default());
+        if bless {
+            if let Err(e) = fs::write(&expected, &actual) {
+                failures.push(format!("{}: {}", expected.display(), e));
+            }
+            continue;
+        }
+        match fs::read_to_string(&expected) {
+            Ok(wanted) if wanted == actual => {}
+            Ok(wanted) => failures.push(format!(
+                "{}: {}",
+                input.display(),
+                first_difference(&wanted, &actual)
+            )),
+            Err(_) => failures.push(format!(
+                "{}: missing {}",
+                input.display(),
+                expected.display()
+            )),
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures),
+    }
+}
+
+/// The input files of `dir`, sorted by name so failures come out in a
+/// stable order.
+fn inputs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut inputs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_none_or(|ext| ext != EXPECTED) {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+fn first_difference(wanted: &str, actual: &str) -> String {
+    let mut wanted_lines = wanted.lines();
+    let mut actual_lines = actual.lines();
+    for line in 1.. {
+        match (wanted_lines.next(), actual_lines.next()) {
+            (Some(w), Some(a)) if w == a => {}
+            (None, None) => break,
+            (w, a) => {
+                return format!(
+                    "line {}: expected {:?}, got {:?}",
+                    line,
+                    w.unwrap_or("<end>"),
+                    a.unwrap_or("<end>")
+                )
+            }
+        }
+    }
+    "line endings differ".to_string()
+}
@@ -0,0 +1,149 @@
+#[cfg(test)]
+mod analysis_tests {
+    use pulp::lexer::builder::{keywords, not, ordered, re, rule, sep_by};
+    use pulp::{Lexer, Recovery, Select, Trivia};
+
+    #[test]
+    fn test_rejects_loops_over_nullable_statements() {
+        let errors = [
+            Lexer::new(re("a*").many()),
+            Lexer::new(re("b").opt().then(re("a*")).many1()),
+            Lexer::new(not(re("a")).repeat(1, None)),
+            Lexer::new(sep_by(re("a*"), re(",").opt(), false, 1)),
+        ];
+        for (error, kind) in errors
+            .into_iter()
+            .zip(["ZeroOrMore", "OneOrMore", "Repeat", "SepBy"])
+        {
+            assert!(error.unwrap_err().starts_with(kind));
+        }
+        assert!(Lexer::new(re("a*").repeat(0, Some(3))).is_ok());
+        assert!(Lexer::new(sep_by(re("a*"), re(","), false, 1)).is_ok());
+
+        let error = Lexer::tokenizer(vec![re("a"), re(r"\s*").many()], Select::First).unwrap_err();
+        assert!(error.starts_with("Rule 2: ZeroOrMore"));
+    }
+
+    #[test]
+    fn test_rejects_empty_repeat_ranges() {
+        assert_eq!(
+            Lexer::new(re("a").repeat(5, Some(2))).unwrap_err(),
+            "Repeat needs at least 5 items but allows at most 2"
+        );
+        assert!(Lexer::new(re("a").repeat(2, Some(2))).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_loops_added_by_builders() {
+        let tokenizer = || Lexer::tokenizer(vec![re("a")], Select::First).unwrap();
+        let lexers = [
+            tokenizer().with_mode("s", vec![re("b"), re("b*").many().save("x")]),
+            tokenizer().with_trivia(re(" *").many(), Trivia::Drop),
+            tokenizer().with_recovery(Recovery::Sync(vec![re(";*").many1()])),
+            tokenizer().with_rule("r", re("a*").many()),
+        ];
+        let prefixes = [
+            "Rule 2 of mode s: ZeroOrMore",
+            "Trivia: ZeroOrMore",
+            "Sync rule 1: OneOrMore",
+            "Rule \"r\": ZeroOrMore",
+        ];
+        for (lexer, prefix) in lexers.iter().zip(prefixes) {
+            assert!(lexer.error().unwrap().starts_with(prefix));
+            assert!(lexer.warnings().is_empty());
+            let error = lexer.lex("aab").unwrap_err();
+            assert_eq!(error.offset, 0);
+            assert!(error.message.starts_with(prefix));
+            assert!(lexer.cst("aab").is_err());
+            assert_eq!(lexer.lex_all("aab").1.len(), 1);
+        }
+        assert_eq!(tokenizer().with_rule("r", re("a*")).error(), None);
+
+        let lexer = tokenizer()
+            .with_mode("s", vec![re("b"), re("b")])
+            .with_rule("w", ordered(vec![re(r"\w+"), keywords(&["if"])]))
+            .with_rule("v", rule("undefined"));
+        assert_eq!(
+            lexer.warnings(),
+            [
+                "Rule 2 of mode s is never used, rule 1 is the same",
+                "Rule \"w\": Alternative 2 of an ordered choice is never tried, alternative 1 \
+                 matches wherever it could",
+                "Rule \"v\" can never match"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rules_are_analysed_where_they_are_used() {
+        // `r` is only known to match empty input once it is defined
+        let lexer = Lexer::new(rule("r").many()).unwrap();
+        assert_eq!(lexer.error(), None);
+        let lexer = lexer.with_rule("r", re("a*"));
+        assert!(lexer.error().unwrap().starts_with("ZeroOrMore"));
+        assert!(lexer.lex("aab").is_err());
+        // redefining it fixes the grammar again
+        let lexer = lexer.with_rule("r", re("a+"));
+        assert_eq!(lexer.error(), None);
+        assert_eq!(lexer.lex("aa").unwrap().len(), 0);
+
+        // through other rules, recursive ones included
+        let lexer = Lexer::new(rule("list").many())
+            .unwrap()
+            .with_rule("list", ordered(vec![rule("item"), rule("list")]))
+            .with_rule("item", re("b").opt());
+        assert!(lexer.error().unwrap().starts_with("ZeroOrMore"));
+        let lexer = lexer.with_rule("item", re("b"));
+        assert_eq!(lexer.error(), None);
+    }
+
+    #[test]
+    fn test_unreachable_alternatives() {
+        let warnings = |alternatives| {
+            Lexer::new(ordered(alternatives).save("item"))
+                .unwrap()
+                .warnings()
+                .to_vec()
+        };
+        assert_eq!(
+            warnings(vec![re("a").opt(), re("b")]),
+            vec!["Alternative 2 of an ordered choice is never tried, alternative 1 always matches"]
+        );
+        assert_eq!(
+            warnings(vec![re("a"), re("b"), re("a")]),
+            vec!["Alternative 3 of an ordered choice is never tried, alternative 1 is the same"]
+        );
+        assert_eq!(
+            warnings(vec![re(r"\w+"), keywords(&["if", "else"])]),
+            vec![
+                "Alternative 2 of an ordered choice is never tried, alternative 1 matches \
+                 wherever it could"
+            ]
+        );
+        assert!(warnings(vec![re("ab"), re("a")]).is_empty());
+        assert!(warnings(vec![keywords(&["if", "else"]), re(r"\w+")]).is_empty());
+        assert!(warnings(vec![re(r"\d+"), re(r"\w+")]).is_empty());
+    }
+
+    #[test]
+    fn test_unused_rules() {
+        let rules = vec![
+            re(r"\w+").save("name"),
+            keywords(&["let"]).save("let"),
+            re(r"\s+"),
+            re(r"\s+"),
+            keywords(&[]),
+        ];
+        let first = Lexer::tokenizer(rules.clone(), Select::First).unwrap();
+        assert_eq!(
+            first.warnings(),
+            [
+                "Rule 2 is never used, rule 1 matches wherever it could",
+                "Rule 4 is never used, rule 3 is the same",
+                "Rule 5 can never match"
+            ]
+        );
+        let longest = Lexer::tokenizer(rules, Select::Longest).unwrap();
+        assert_eq!(longest.warnings().len(), 2);
+    }
+}
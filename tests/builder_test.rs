@@ -0,0 +1,89 @@
+#[cfg(test)]
+mod builder_tests {
+    use pulp::lexer::builder::{re, seq};
+    use pulp::{grammar, Regex, Statement};
+
+    fn reader(pattern: &str) -> Statement {
+        Statement::Reader(Regex::new(pattern).unwrap())
+    }
+
+    #[test]
+    fn test_then() {
+        let built = re("a").then(re("b")).then(re("c"));
+        assert_eq!(
+            built,
+            Statement::Concat(vec![reader("a"), reader("b"), reader("c")])
+        );
+    }
+
+    #[test]
+    fn test_add_operator() {
+        assert_eq!(
+            re("a") + re("b") + re("c"),
+            seq(vec![re("a"), re("b"), re("c")])
+        );
+    }
+
+    #[test]
+    fn test_or_operator() {
+        assert_eq!(
+            re("a") | re("b"),
+            Statement::Alternation(Box::new(reader("a")), Box::new(reader("b")))
+        );
+    }
+
+    #[test]
+    fn test_repetition() {
+        assert_eq!(re("a").opt(), Statement::ZeroOrOne(Box::new(reader("a"))));
+        assert_eq!(re("a").many(), Statement::ZeroOrMore(Box::new(reader("a"))));
+        assert_eq!(re("a").many1(), Statement::OneOrMore(Box::new(reader("a"))));
+    }
+
+    #[test]
+    fn test_save_and_when() {
+        assert_eq!(
+            re(r"\w+").when(re("@")).save("ident"),
+            Statement::Save(
+                "ident".to_string(),
+                Box::new(Statement::Condition {
+                    cond: Box::new(reader("@")),
+                    then: Box::new(reader(r"\w+")),
+                    otherwise: None,
+                })
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_pattern() {
+        re("(a");
+    }
+
+    #[test]
+    fn test_grammar_sequence() {
+        assert_eq!(grammar!("@" r"\w+"), re("@") + re(r"\w+"));
+    }
+
+    #[test]
+    fn test_grammar_alternation() {
+        assert_eq!(grammar!("a" "b" | "c"), (re("a") + re("b")) | re("c"));
+    }
+
+    #[test]
+    fn test_grammar_postfix_and_groups() {
+        assert_eq!(
+            grammar!("a"? ("b" | "c")* "d"+),
+            re("a").opt() + (re("b") | re("c")).many() + re("d").many1()
+        );
+    }
+
+    #[test]
+    fn test_grammar_save_and_embed() {
+        let block = re(r"\s*{") + re(r"\s*}");
+        assert_eq!(
+            grammar!(ident: r"\w+" block: { block.clone() }?),
+            re(r"\w+").save("ident") + block.clone().save("block").opt()
+        );
+    }
+}
@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod cst_tests {
+    use pulp::lexer::builder::re;
+    use pulp::lexer::cst::{SyntaxElement, TokenKind};
+    use pulp::{Lexer, Recovery, Select, Trivia};
+
+    #[test]
+    fn test_structure_mode_is_lossless() {
+        let lexer =
+            Lexer::new(re("@") + re(r"\w+").save("name") + (re("{") + re("}")).save("block"))
+                .unwrap()
+                .with_trivia(re(r"\s+"), Trivia::Drop);
+        let input = " @lexer {\n} trailing!";
+        let tree = lexer.cst(input).unwrap();
+        assert_eq!(tree.to_string(), input);
+        assert_eq!((tree.start(), tree.end()), (0, input.len()));
+
+        let tokens = tree.tokens();
+        let kinds: Vec<(TokenKind, &str)> = tokens.iter().map(|t| (t.kind(), t.text())).collect();
+        assert_eq!(kinds[0], (TokenKind::Trivia, " "));
+        assert_eq!(kinds[1], (TokenKind::Text, "@"));
+        assert_eq!(kinds.last().unwrap(), &(TokenKind::Unmatched, "trailing!"));
+    }
+
+    #[test]
+    fn test_named_nodes_and_offsets() {
+        let lexer = Lexer::new(re("a") + (re("b") + re("c")).save("bc")).unwrap();
+        let tree = lexer.cst("abc").unwrap();
+        let children = tree.children();
+        let SyntaxElement::Node(node) = &children[1] else {
+            panic!("expected a node");
+        };
+        assert_eq!(node.name(), "bc");
+        assert_eq!((node.start(), node.end()), (1, 3));
+        assert_eq!(node.to_string(), "bc");
+        assert_eq!(node.parent().unwrap().name(), "");
+        let tokens = node.tokens();
+        assert_eq!((tokens[1].text(), tokens[1].start()), ("c", 2));
+        assert_eq!(tokens[1].parent().name(), "bc");
+    }
+
+    #[test]
+    fn test_tokens_mode_is_lossless() {
+        let lexer = Lexer::tokenizer(
+            vec![re(r"\w+").save("ident"), re(r"\d+").save("number")],
+            Select::First,
+        )
+        .unwrap()
+        .with_trivia(re(r"\s+").save("ws"), Trivia::Drop);
+        let input = "  let x 42 \n";
+        let tree = lexer.cst(input).unwrap();
+        assert_eq!(tree.to_string(), input);
+        let trivia: Vec<&str> = tree
+            .tokens()
+            .into_iter()
+            .filter(|t| t.kind() == TokenKind::Trivia)
+            .map(|t| &input[t.start()..t.end()])
+            .collect();
+        assert_eq!(trivia, vec!["  ", " ", " ", " \n"]);
+    }
+
+    #[test]
+    fn test_recovered_input_is_kept() {
+        let lexer = Lexer::tokenizer(vec![re(r"\w+").save("word")], Select::First)
+            .unwrap()
+            .with_trivia(re(r"\s+"), Trivia::Drop)
+            .with_recovery(Recovery::SkipChar);
+        let input = "a ?b";
+        assert!(lexer.lex(input).is_ok());
+        let tree = lexer.cst(input).unwrap();
+        assert_eq!(tree.to_string(), input);
+        let names: Vec<String> = tree
+            .children()
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(node) => Some(node.name().to_string()),
+                SyntaxElement::Token(_) => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["word", "error", "word"]);
+    }
+
+    #[test]
+    fn test_left_over_input_is_an_error_with_recovery() {
+        let lexer = Lexer::new(re(r"\w+").save("word"))
+            .unwrap()
+            .with_trivia(re(r"\s+"), Trivia::Drop)
+            .with_recovery(Recovery::SkipChar);
+        let tree = lexer.cst("abc ?!").unwrap();
+        assert_eq!(tree.to_string(), "abc ?!");
+        let SyntaxElement::Node(error) = tree.children().last().unwrap().clone() else {
+            panic!("expected a node");
+        };
+        assert_eq!((error.name(), error.start()), ("error", 4));
+        assert!(tree
+            .tokens()
+            .iter()
+            .all(|t| t.kind() != TokenKind::Unmatched));
+    }
+}
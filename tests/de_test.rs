@@ -0,0 +1,151 @@
+#[cfg(all(test, feature = "serde"))]
+mod de_tests {
+    use pulp::lexer::builder::{choice, re, sep_by};
+    use pulp::lexer::de::Error;
+    use pulp::{from_tokens, Lexer, Transform};
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Decl<'a> {
+        name: &'a str,
+        ty: Option<String>,
+        args: Vec<Arg>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Arg {
+        name: String,
+        size: u32,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Sized {
+        name: String,
+        #[serde(default)]
+        size: u32,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Sizes {
+        #[serde(default)]
+        size: u32,
+        args: Vec<Sized>,
+    }
+
+    fn decl_lexer() -> Lexer {
+        let arg = (re(r"\w+").save("name") + re(":") + re(r"\d+").save("size")).save("args");
+        let decl = re(r"fn ")
+            + re(r"\w+").save("name")
+            + re(r"\(")
+            + sep_by(arg, re(r", "), false, 0)
+            + re(r"\)")
+            + (re(r" -> ") + re(r"\w+").save("ty")).opt();
+        Lexer::new(decl.save("decl")).unwrap()
+    }
+
+    #[test]
+    fn test_struct_fields() {
+        let lexer = decl_lexer();
+        let tokens = lexer.lex("fn copy(src:8, dst:16) -> unit").unwrap();
+        let decl: Decl = from_tokens(&tokens[0]).unwrap();
+        assert_eq!(
+            decl,
+            Decl {
+                name: "copy",
+                ty: Some("unit".to_string()),
+                args: vec![
+                    Arg {
+                        name: "src".to_string(),
+                        size: 8
+                    },
+                    Arg {
+                        name: "dst".to_string(),
+                        size: 16
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_optional_and_repeated_fields() {
+        let lexer = decl_lexer();
+        let tokens = lexer.lex("fn nop()").unwrap();
+        let decl: Decl = from_tokens(&tokens[0]).unwrap();
+        assert_eq!(
+            decl,
+            Decl {
+                name: "nop",
+                ty: None,
+                args: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let lexer = decl_lexer();
+        let tokens = lexer.lex("fn f(a:1)").unwrap();
+        let error = from_tokens::<Arg>(&tokens[0]).unwrap_err();
+        assert_eq!(
+            error,
+            Error::Missing {
+                field: "size".to_string(),
+                token: Some(("decl".to_string(), 0)),
+            }
+        );
+        assert_eq!(error.to_string(), "Missing size in decl at offset 0");
+    }
+
+    #[test]
+    fn test_default_fields() {
+        let lexer = decl_lexer();
+        let tokens = lexer.lex("fn f(a:1)").unwrap();
+        assert_eq!(
+            from_tokens::<Sized>(&tokens[0]).unwrap(),
+            Sized {
+                name: "f".to_string(),
+                size: 0
+            }
+        );
+        let arg = tokens[0].children.iter().find(|c| c.name == "args");
+        assert_eq!(
+            from_tokens::<Sized>(arg.unwrap()).unwrap(),
+            Sized {
+                name: "a".to_string(),
+                size: 1
+            }
+        );
+        let tokens = lexer.lex("fn f()").unwrap();
+        assert_eq!(
+            from_tokens::<Sizes>(&tokens[0]).unwrap(),
+            Sizes {
+                size: 0,
+                args: vec![]
+            }
+        );
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Literal {
+        Int(i64),
+        Word(String),
+    }
+
+    #[test]
+    fn test_enum_and_values() {
+        let literal = choice(vec![
+            re(r"\d+").save_with("int", &[Transform::Int]),
+            re(r"\w+").save("word"),
+        ]);
+        let lexer = Lexer::new(literal.save("literal")).unwrap();
+        let int = &lexer.lex("42").unwrap()[0];
+        assert_eq!(from_tokens::<Literal>(int).unwrap(), Literal::Int(42));
+        let word = &lexer.lex("abc").unwrap()[0];
+        assert_eq!(
+            from_tokens::<Literal>(word).unwrap(),
+            Literal::Word("abc".to_string())
+        );
+    }
+}
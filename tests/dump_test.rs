@@ -0,0 +1,101 @@
+#[cfg(test)]
+mod dump_tests {
+    use pulp::lexer::builder::re;
+    use pulp::{DumpOptions, Lexer, Transform};
+
+    fn assignment() -> Lexer {
+        let value = re(r"\d+").save_with("value", &[Transform::Int]);
+        Lexer::new((re(r"\w+").save("name") + re(" = ") + value).save("assign")).unwrap()
+    }
+
+    #[test]
+    fn test_to_json() {
+        let tokens = assignment().lex("x = 42").unwrap();
+        let options = DumpOptions {
+            text: false,
+            ..DumpOptions::default()
+        };
+        assert_eq!(
+            tokens[0].to_json(options),
+            r#"{
+  "name": "assign",
+  "start": 0,
+  "end": 6,
+  "children": [
+    {
+      "name": "name",
+      "start": 0,
+      "end": 1,
+      "children": [
+        {
+          "name": "",
+          "start": 0,
+          "end": 1
+        }
+      ]
+    },
+    {
+      "name": "",
+      "start": 1,
+      "end": 4
+    },
+    {
+      "name": "value",
+      "start": 4,
+      "end": 6,
+      "value": 42,
+      "children": [
+        {
+          "name": "",
+          "start": 4,
+          "end": 6
+        }
+      ]
+    }
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn test_to_sexpr() {
+        let tokens = assignment().lex("x = 42").unwrap();
+        assert_eq!(
+            tokens[0].to_sexpr(DumpOptions::default()),
+            r#"(assign :span 0 6 :text "x = 42"
+  (name :span 0 1 :text "x"
+    (_ :span 0 1 :text "x"))
+  (_ :span 1 4 :text " = ")
+  (value :span 4 6 :text "42" :value 42
+    (_ :span 4 6 :text "42")))"#
+        );
+        let bare = DumpOptions {
+            spans: false,
+            text: false,
+        };
+        assert!(tokens[0]
+            .to_sexpr(bare)
+            .starts_with("(assign\n  (name\n    (_))"));
+    }
+
+    #[test]
+    fn test_json_escapes() {
+        let lexer = Lexer::new(re(r#""\w*\n""#).save("s")).unwrap();
+        let tokens = lexer.lex("\"a\n\"").unwrap();
+        let json = tokens[0].to_json(DumpOptions::default());
+        assert!(json.contains(r#""text": "\"a\n\"""#));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let tokens = assignment().lex("x = 42").unwrap();
+        let json = serde_json::to_value(&tokens[0]).unwrap();
+        assert_eq!(json["name"], "assign");
+        assert_eq!(json["children"][2]["value"], 42);
+        assert_eq!(json["children"][2]["text"], "42");
+        assert!(json.get("value").is_none());
+        let owned = serde_json::to_value(tokens[0].to_owned_token()).unwrap();
+        assert_eq!(json, owned);
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod incremental_tests {
+    use pulp::lexer::builder::{pop_mode, push_mode, re};
+    use pulp::lexer::incremental::Edit;
+    use pulp::{Lexer, Recovery, Select, Trivia};
+
+    fn lexer() -> Lexer {
+        Lexer::tokenizer(
+            vec![
+                re(r"\w+").save("ident"),
+                re(r"\d+").save("number"),
+                re("\"").save("open") + push_mode("string"),
+            ],
+            Select::Longest,
+        )
+        .unwrap()
+        .with_mode(
+            "string",
+            vec![
+                re("\"").save("close") + pop_mode(),
+                re(r"(\w|\s)+").save("chars"),
+            ],
+        )
+        .with_trivia(re(r"\s+"), Trivia::Attach)
+        .with_recovery(Recovery::SkipChar)
+    }
+
+    #[test]
+    fn test_relex_reuses_tokens_around_the_edit() {
+        let lexer = lexer();
+        let old = "let x = \"a b\" 42";
+        let lexed = lexer.lex_incremental(old).unwrap();
+        let edit = Edit::new(4, 5, "yy");
+        let input = edit.apply(old).unwrap();
+        let relexed = lexer.relex(&lexed, &edit, &input).unwrap();
+        assert_eq!(relexed.tokens, lexer.lex(&input).unwrap());
+        assert_eq!(relexed.tokens[1].text, "yy");
+        assert_eq!(relexed.tokens.last().unwrap().start, 15);
+    }
+
+    #[test]
+    fn test_relex_across_modes() {
+        let lexer = lexer();
+        let old = "a \"b c\" d";
+        let lexed = lexer.lex_incremental(old).unwrap();
+        // opening a string turns the rest of the input into string content
+        let edit = Edit::new(0, 0, "\"");
+        let input = edit.apply(old).unwrap();
+        let relexed = lexer.relex(&lexed, &edit, &input).unwrap();
+        assert_eq!(relexed.tokens, lexer.lex(&input).unwrap());
+    }
+
+    #[test]
+    fn test_relex_rejects_mismatched_edit() {
+        let lexer = lexer();
+        let lexed = lexer.lex_incremental("abc").unwrap();
+        assert!(lexer.relex(&lexed, &Edit::new(0, 1, "xy"), "abc").is_err());
+    }
+
+    #[test]
+    fn test_edit_out_of_range() {
+        let lexer = lexer();
+        let lexed = lexer.lex_incremental("abc").unwrap();
+        for edit in [Edit::new(2, 1, ""), Edit::new(2, 5, "")] {
+            assert!(edit.apply("abc").is_err());
+            assert!(lexer.relex(&lexed, &edit, "abc").is_err());
+        }
+        assert!(Edit::new(1, 1, "").apply("äb").is_err());
+
+        let lexed = lexer.lex_incremental("äb").unwrap();
+        assert!(lexer.relex(&lexed, &Edit::new(1, 1, ""), "äb").is_err());
+    }
+
+    #[test]
+    fn test_relex_equals_full_lex() {
+        let lexer = lexer();
+        let pieces = ["a", "bc", "1", "23", " ", "\n", "\"", "?", "x y", ""];
+        let mut seed: u64 = 0x2545f4914f6cdd1d;
+        let mut random = move |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        // every input stays borrowed by the result lexed from it
+        let leak = |s: String| -> &'static str { Box::leak(s.into_boxed_str()) };
+        let mut input = leak("let x = \"a b\" 42 \"c\" d".to_string());
+        let mut old = lexer.lex_incremental(input).unwrap();
+        for _ in 0..500 {
+            let start = random(input.len() + 1);
+            let end = start + random(input.len() - start + 1).min(3);
+            let edit = Edit::new(start, end, pieces[random(pieces.len())]);
+            let next = leak(edit.apply(input).unwrap());
+            let relexed = lexer.relex(&old, &edit, next).unwrap();
+            assert_eq!(
+                relexed.tokens,
+                lexer.lex(next).unwrap(),
+                "{:?} applied to {:?}",
+                edit,
+                input
+            );
+            old = relexed;
+            input = next;
+        }
+    }
+}
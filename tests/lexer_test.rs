@@ -0,0 +1,1117 @@
+#[cfg(test)]
+mod lexer_tests {
+    use pulp::lexer::builder::{
+        and, choice, delimited, if_else, keywords, longest, not, ordered, pop_mode, precedence,
+        push_mode, re, rule, sep_by,
+    };
+    use pulp::lexer::instruction::{LexError, Token};
+    use pulp::lexer::trie::Trie;
+    use pulp::{DumpOptions, Fixity, Lexer, OpDef, Recovery, Select, Transform, Trivia, Value};
+
+    fn names<'a>(tokens: &'a [Token]) -> Vec<(&'a str, &'a str)> {
+        tokens.iter().map(|t| (t.name.as_str(), t.text)).collect()
+    }
+
+    #[test]
+    fn test_structure_mode() {
+        let lexer = Lexer::new(re("@") + re(r"\w+").save("ident")).unwrap();
+        let tokens = lexer.lex("@merge").unwrap();
+        assert_eq!(names(&tokens), vec![("ident", "merge")]);
+        assert_eq!((tokens[0].start, tokens[0].end), (1, 6));
+    }
+
+    #[test]
+    fn test_structure_mode_failure() {
+        let lexer = Lexer::new(re("@") + re(r"\w+").save("ident")).unwrap();
+        assert_eq!(lexer.lex("merge").unwrap_err().offset, 0);
+    }
+
+    #[test]
+    fn test_tokens_mode() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                re(r"\w+").save("ident"),
+                re(r"\d+").save("number"),
+                re(r"\s+"),
+            ],
+            Select::First,
+        )
+        .unwrap();
+        let tokens = lexer.lex("let x 42").unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![("ident", "let"), ("ident", "x"), ("number", "42")]
+        );
+        assert_eq!((tokens[2].start, tokens[2].end), (6, 8));
+    }
+
+    #[test]
+    fn test_tokens_mode_first_vs_longest() {
+        let rules = vec![re("=").save("assign"), re("==").save("eq")];
+        let first = Lexer::tokenizer(rules.clone(), Select::First).unwrap();
+        let longest = Lexer::tokenizer(rules, Select::Longest).unwrap();
+        assert_eq!(
+            names(&first.lex("==").unwrap()),
+            vec![("assign", "="), ("assign", "=")]
+        );
+        assert_eq!(names(&longest.lex("==").unwrap()), vec![("eq", "==")]);
+    }
+
+    #[test]
+    fn test_tokens_mode_error() {
+        let lexer = Lexer::tokenizer(vec![re(r"\w+").save("ident")], Select::First).unwrap();
+        let err = lexer.lex("ab!").unwrap_err();
+        assert_eq!(err.offset, 2);
+    }
+
+    #[test]
+    fn test_tokens_iterator_is_lazy() {
+        let lexer =
+            Lexer::tokenizer(vec![re(r"\w+").save("ident"), re(r"\s+")], Select::First).unwrap();
+        let mut tokens = lexer.tokens("ab cd !ef");
+        assert_eq!(tokens.next().unwrap().unwrap().text, "ab");
+        assert_eq!(tokens.next().unwrap().unwrap().text, "cd");
+        assert_eq!(tokens.next().unwrap().unwrap_err().offset, 6);
+        assert!(tokens.next().is_none());
+    }
+
+    #[test]
+    fn test_tokens_multibyte_input() {
+        let lexer =
+            Lexer::tokenizer(vec![re(r"\w+").save("ident"), re(r"\s+")], Select::First).unwrap();
+        let tokens: Vec<Token> = lexer.tokens("héllo wörld").map(Result::unwrap).collect();
+        assert_eq!(names(&tokens), vec![("ident", "héllo"), ("ident", "wörld")]);
+        assert_eq!((tokens[1].start, tokens[1].end), (7, 13));
+    }
+
+    #[test]
+    fn test_tokens_borrow_input() {
+        let input = String::from("ab cd");
+        let lexer =
+            Lexer::tokenizer(vec![re(r"\w+").save("ident"), re(r"\s+")], Select::First).unwrap();
+        let tokens = lexer.lex(&input).unwrap();
+        assert_eq!(tokens[1].text.as_ptr(), input[3..].as_ptr());
+        let owned = tokens[1].to_owned_token();
+        assert_eq!((owned.text.as_str(), owned.start, owned.end), ("cd", 3, 5));
+    }
+
+    #[test]
+    fn test_trivia_dropped_in_structure_mode() {
+        let lexer = Lexer::new(re(r"\w+").save("name") + (re("{") + re("}")).save("block"))
+            .unwrap()
+            .with_trivia(re(r"\s+"), Trivia::Drop);
+        let tokens = lexer.lex("lexer  {\n }").unwrap();
+        assert_eq!(names(&tokens), vec![("name", "lexer"), ("block", "{\n }")]);
+        assert_eq!((tokens[1].start, tokens[1].end), (7, 11));
+        assert!(tokens[1].leading.is_empty());
+    }
+
+    #[test]
+    fn test_trivia_attached_in_tokens_mode() {
+        let lexer = Lexer::tokenizer(vec![re(r"\w+").save("ident")], Select::First)
+            .unwrap()
+            .with_trivia(
+                re(r"\s+").save("ws") | (re("#") + re(r"\S*")).save("comment"),
+                Trivia::Attach,
+            );
+        let tokens = lexer.lex(" a #note b ").unwrap();
+        assert_eq!(names(&tokens), vec![("ident", "a"), ("ident", "b")]);
+        assert_eq!(names(&tokens[0].leading), vec![("ws", " ")]);
+        assert_eq!(
+            names(&tokens[1].leading),
+            vec![("ws", " "), ("comment", "#note"), ("ws", " ")]
+        );
+        assert_eq!(names(&tokens[1].trailing), vec![("ws", " ")]);
+    }
+
+    #[test]
+    fn test_trivia_attached_inside_capture() {
+        let lexer = Lexer::new((re("a") + re("b").save("b")).save("pair"))
+            .unwrap()
+            .with_trivia(re(" +"), Trivia::Attach);
+        let tokens = lexer.lex("  a b").unwrap();
+        assert_eq!(names(&tokens[0].leading), vec![("", "  ")]);
+        assert_eq!(names(&tokens[0].children[1].leading), vec![("", " ")]);
+        assert_eq!(tokens[0].text, "a b");
+    }
+
+    #[test]
+    fn test_trivia_not_skipped_inside_a_token() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                (re(r"\d+") + re(r"\.") + re(r"\d+")).save("number"),
+                re(r"\d+|\.").save("part"),
+            ],
+            Select::First,
+        )
+        .unwrap()
+        .with_trivia(re(r"\s+"), Trivia::Drop);
+        let tokens = lexer.lex("1.5 1 . 5").unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![
+                ("number", "1.5"),
+                ("part", "1"),
+                ("part", "."),
+                ("part", "5")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trivia_not_skipped_inside_lookahead() {
+        let keyword = (re("if") + not(re(r"\w"))).save("keyword");
+        let lexer = Lexer::tokenizer(
+            vec![keyword.clone(), re(r"\w+").save("ident")],
+            Select::First,
+        )
+        .unwrap()
+        .with_trivia(re(r"\s+"), Trivia::Drop);
+        let tokens = lexer.lex("if x iffy").unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![("keyword", "if"), ("ident", "x"), ("ident", "iffy")]
+        );
+        let lexer = Lexer::new(keyword + re(r"\w+").save("ident"))
+            .unwrap()
+            .with_trivia(re(r"\s+"), Trivia::Drop);
+        let tokens = lexer.lex("if x").unwrap();
+        assert_eq!(names(&tokens), vec![("keyword", "if"), ("ident", "x")]);
+    }
+
+    fn template_lexer() -> Lexer {
+        Lexer::tokenizer(
+            vec![
+                re(r"\w+").save("ident"),
+                re(r"\s+"),
+                re("\"").save("open") + push_mode("string"),
+            ],
+            Select::First,
+        )
+        .unwrap()
+        .with_mode(
+            "string",
+            vec![
+                re("\"").save("close") + pop_mode(),
+                re(r"\$\{").save("interp") + push_mode("code"),
+                re(r"(\w|\s)+").save("chars"),
+            ],
+        )
+        .with_mode(
+            "code",
+            vec![
+                re("}").save("end") + pop_mode(),
+                re(r"\w+").save("ident"),
+                re(r"\s+"),
+                re("\"").save("open") + push_mode("string"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_mode_stack_nests() {
+        let tokens = template_lexer()
+            .lex(r#"say "hi ${name "x${y}"} yo" done"#)
+            .unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![
+                ("ident", "say"),
+                ("open", "\""),
+                ("chars", "hi "),
+                ("interp", "${"),
+                ("ident", "name"),
+                ("open", "\""),
+                ("chars", "x"),
+                ("interp", "${"),
+                ("ident", "y"),
+                ("end", "}"),
+                ("close", "\""),
+                ("end", "}"),
+                ("chars", " yo"),
+                ("close", "\""),
+                ("ident", "done"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mode_errors() {
+        let lexer = Lexer::tokenizer(vec![re("a") + push_mode("missing")], Select::First).unwrap();
+        assert_eq!(lexer.lex("a").unwrap_err().message, "Unknown mode missing");
+        let lexer = Lexer::tokenizer(vec![re("a") + pop_mode()], Select::First).unwrap();
+        assert!(lexer.lex("a").is_err());
+    }
+
+    #[test]
+    fn test_condition() {
+        let lexer = Lexer::new((re("@") + re(r"\w+")).when(re("@")).save("tag")).unwrap();
+        assert_eq!(
+            names(&lexer.lex("@merge").unwrap()),
+            vec![("tag", "@merge")]
+        );
+        let error = lexer.lex("merge").unwrap_err();
+        assert_eq!(error.offset, 0);
+        assert_eq!(error.message, "Unexpected input after the grammar");
+    }
+
+    #[test]
+    fn test_condition_otherwise() {
+        let lexer = Lexer::new(
+            if_else(
+                re(r"\d"),
+                re(r"\d+").save("number"),
+                re(r"\w+").save("word"),
+            )
+            .many1(),
+        )
+        .unwrap();
+        assert_eq!(
+            names(&lexer.lex("abc12de").unwrap()),
+            vec![("word", "abc"), ("number", "12"), ("word", "de")]
+        );
+    }
+
+    #[test]
+    fn test_not_keyword() {
+        let keyword = (re("if") + not(re(r"\w"))).save("keyword");
+        let lexer = Lexer::tokenizer(
+            vec![keyword, re(r"\w+").save("ident"), re(r"\s+")],
+            Select::First,
+        )
+        .unwrap();
+        assert_eq!(
+            names(&lexer.lex("if iffy").unwrap()),
+            vec![("keyword", "if"), ("ident", "iffy")]
+        );
+    }
+
+    #[test]
+    fn test_and_does_not_consume() {
+        let lexer =
+            Lexer::new(re(r"\w+").save("name") + and(re(r"\(")) + re(r"\(").save("open")).unwrap();
+        assert_eq!(
+            names(&lexer.lex("call(").unwrap()),
+            vec![("name", "call"), ("open", "(")]
+        );
+        assert!(lexer.lex("call ").is_err());
+    }
+
+    #[test]
+    fn test_lookahead_with_alternatives() {
+        // the first alternative of the lookahead fails, the second one matches
+        let lexer = Lexer::new(re(r"\w+").save("word").when(re("x") | re("a"))).unwrap();
+        assert_eq!(names(&lexer.lex("abc").unwrap()), vec![("word", "abc")]);
+        let lexer = Lexer::new(re(r"\w+").save("word") + not(re("x") | re("a"))).unwrap();
+        assert_eq!(names(&lexer.lex("abc").unwrap()), vec![("word", "abc")]);
+    }
+
+    #[test]
+    fn test_failed_branch_rolls_back_captures() {
+        let lexer =
+            Lexer::new((re("x") + re("y")).save("xy") | (re("x").save("x") + re("z")).save("xz"))
+                .unwrap();
+        let tokens = lexer.lex("xz").unwrap();
+        assert_eq!(names(&tokens), vec![("xz", "xz")]);
+        assert_eq!(names(&tokens[0].children), vec![("x", "x"), ("", "z")]);
+    }
+
+    #[test]
+    fn test_ordered_choice_commits() {
+        let backtracking = Lexer::new((re("a") | re("ab")).save("head") + re("c")).unwrap();
+        assert_eq!(
+            names(&backtracking.lex("abc").unwrap()),
+            vec![("head", "ab")]
+        );
+
+        let peg = Lexer::new(ordered(vec![re("a"), re("ab")]).save("head") + re("c")).unwrap();
+        assert!(peg.lex("abc").is_err());
+        assert_eq!(names(&peg.lex("ac").unwrap()), vec![("head", "a")]);
+    }
+
+    #[test]
+    fn test_ordered_choice_falls_through() {
+        let lexer = Lexer::new(
+            ordered(vec![
+                re(r"\d+").save("number"),
+                re(r"\w+").save("word"),
+                re(r"\s+"),
+            ])
+            .many1(),
+        )
+        .unwrap();
+        assert_eq!(
+            names(&lexer.lex("ab 12 cd").unwrap()),
+            vec![("word", "ab"), ("number", "12"), ("word", "cd")]
+        );
+    }
+
+    #[test]
+    fn test_empty_ordered_choice_fails() {
+        let lexer = Lexer::new(re("a") + ordered(vec![]) + re("b")).unwrap();
+        assert!(lexer.lex("ab").is_err());
+    }
+
+    #[test]
+    fn test_choice() {
+        let lexer = Lexer::new(
+            choice(vec![
+                re(r"\d+").save("number"),
+                re(r"\w+").save("word"),
+                re(r"\s+"),
+            ])
+            .many1(),
+        )
+        .unwrap();
+        assert_eq!(
+            names(&lexer.lex("ab 12").unwrap()),
+            vec![("word", "ab"), ("number", "12")]
+        );
+    }
+
+    #[test]
+    fn test_choice_backtracks() {
+        let lexer =
+            Lexer::new(choice(vec![re("a"), re("ab"), re("abc")]).save("head") + re("c")).unwrap();
+        assert_eq!(names(&lexer.lex("abc").unwrap()), vec![("head", "ab")]);
+    }
+
+    #[test]
+    fn test_empty_choice_fails() {
+        let lexer = Lexer::new(re("a") + choice(vec![]) + re("b")).unwrap();
+        assert!(lexer.lex("ab").is_err());
+    }
+
+    #[test]
+    fn test_trie_longest_match() {
+        let trie = Trie::new(&["in", "int", "interface", "ünï"]);
+        assert_eq!(trie.longest_match("integer"), Some(3));
+        assert_eq!(trie.longest_match("interfaces"), Some(9));
+        assert_eq!(trie.longest_match("ink"), Some(2));
+        assert_eq!(trie.longest_match("ünïcode"), Some(5));
+        assert_eq!(trie.longest_match("out"), None);
+    }
+
+    #[test]
+    fn test_keywords() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                (keywords(&["if", "in", "int", "else"]) + not(re(r"\w"))).save("keyword"),
+                re(r"\w+").save("ident"),
+                re(r"\s+"),
+            ],
+            Select::First,
+        )
+        .unwrap();
+        assert_eq!(
+            names(&lexer.lex("int x in ifs").unwrap()),
+            vec![
+                ("keyword", "int"),
+                ("ident", "x"),
+                ("keyword", "in"),
+                ("ident", "ifs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_longest_alternative() {
+        let op = longest(vec![
+            re("=").save("eq"),
+            re("==").save("eqeq"),
+            re("=>").save("arrow"),
+        ]);
+        let lexer = Lexer::tokenizer(vec![op, re(r"\s+")], Select::First).unwrap();
+        let tokens = lexer.lex("= == =>").unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![("eq", "="), ("eqeq", "=="), ("arrow", "=>")]
+        );
+    }
+
+    #[test]
+    fn test_longest_reports_ties() {
+        let word = longest(vec![
+            re(r"\w+").save("ident"),
+            re("if").save("keyword"),
+            re("i").save("letter"),
+        ]);
+        let lexer = Lexer::tokenizer(vec![word, re(r"\s+")], Select::First).unwrap();
+        let (tokens, ambiguities) = lexer.lex_debug("if iffy");
+        assert_eq!(
+            names(&tokens.unwrap()),
+            vec![("ident", "if"), ("ident", "iffy")]
+        );
+        assert_eq!(ambiguities.len(), 1);
+        assert_eq!(ambiguities[0].offset, 0);
+        assert_eq!(ambiguities[0].len, 2);
+        assert_eq!(ambiguities[0].alternatives, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_ties_of_failed_branches_are_rolled_back() {
+        let word = longest(vec![re(r"\w+").save("word"), re(r"\w+").save("name")]);
+        let plain = Lexer::new((re("a") | re("a")) + word + re("!")).unwrap();
+        for lexer in [plain.clone(), plain.with_memo(100)] {
+            let (tokens, ambiguities) = lexer.lex_debug("ab?");
+            assert!(tokens.is_err());
+            assert!(ambiguities.is_empty());
+            let (tokens, ambiguities) = lexer.lex_debug("ab!");
+            assert_eq!(names(&tokens.unwrap()), vec![("word", "b")]);
+            assert_eq!(ambiguities.len(), 1);
+            assert_eq!(ambiguities[0].offset, 1);
+        }
+    }
+
+    #[test]
+    fn test_ties_of_longer_rules_are_not_reported() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                longest(vec![re(r"\w").save("letter"), re(r"\w").save("char")]),
+                re(r"\w+").save("word"),
+            ],
+            Select::Longest,
+        )
+        .unwrap();
+        let (tokens, ambiguities) = lexer.lex_debug("ab");
+        assert_eq!(names(&tokens.unwrap()), vec![("word", "ab")]);
+        assert!(ambiguities.is_empty());
+    }
+
+    #[test]
+    fn test_longest_rules_report_ties() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                re("let").save("keyword"),
+                re(r"\w+").save("ident"),
+                re(r"\s+"),
+            ],
+            Select::Longest,
+        )
+        .unwrap();
+        let (tokens, ambiguities) = lexer.lex_debug("let x");
+        assert_eq!(
+            names(&tokens.unwrap()),
+            vec![("keyword", "let"), ("ident", "x")]
+        );
+        assert_eq!(ambiguities.len(), 1);
+        assert_eq!(ambiguities[0].alternatives, vec![0, 1]);
+        assert!(lexer.lex_debug("x").1.is_empty());
+    }
+
+    fn arguments(trailing: bool, min: usize) -> Lexer {
+        let item = re(r"\w+").save("arg");
+        Lexer::new(re(r"\(") + sep_by(item, re(r",\s*"), trailing, min) + re(r"\)")).unwrap()
+    }
+
+    #[test]
+    fn test_sep_by() {
+        let lexer = arguments(false, 0);
+        let tokens = lexer.lex("(a, b, c)").unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![("arg", "a"), ("arg", "b"), ("arg", "c")]
+        );
+        assert!(lexer.lex("()").unwrap().is_empty());
+        assert!(lexer.lex("(a, b,)").is_err());
+        assert!(lexer.lex("(a,, b)").is_err());
+    }
+
+    #[test]
+    fn test_sep_by_trailing_and_min() {
+        let lexer = arguments(true, 2);
+        assert_eq!(lexer.lex("(a, b,)").unwrap().len(), 2);
+        assert_eq!(lexer.lex("(a, b, c)").unwrap().len(), 3);
+        assert!(lexer.lex("(a)").is_err());
+        assert!(lexer.lex("()").is_err());
+    }
+
+    #[test]
+    fn test_delimited() {
+        let block = delimited(
+            re(r"\{\s*"),
+            (re(r"\w+").save("stmt") + re(r";\s*")).many(),
+            re(r"\}"),
+        );
+        let lexer = Lexer::tokenizer(vec![block, re(r"\s+")], Select::First).unwrap();
+        assert!(lexer.lex("{ a; b; } { c; ?! }").is_err());
+
+        let lexer = lexer.with_recovery(Recovery::SkipChar);
+        let (tokens, errors) = lexer.lex_all("{ a; b; } { c; ?! }");
+        assert_eq!(
+            names(&tokens),
+            vec![("stmt", "a"), ("stmt", "b"), ("error", "c; ?! ")]
+        );
+        assert_eq!(errors, vec![LexError::new(12, "Unexpected input")]);
+    }
+
+    #[test]
+    fn test_delimited_needs_recovery_to_skip() {
+        let block = delimited(re(r"\{"), re(r"\d+").save("n"), re(r"\}")).save("block");
+        assert!(Lexer::new(block.clone()).unwrap().lex("{abc}").is_err());
+
+        let lexer = Lexer::new(block).unwrap().with_recovery(Recovery::SkipChar);
+        let (tokens, errors) = lexer.lex_all("{abc}");
+        assert_eq!(
+            names(&tokens[0].children),
+            vec![("", "{"), ("error", "abc"), ("", "}")]
+        );
+        assert_eq!(errors, vec![LexError::new(1, "Unexpected input")]);
+    }
+
+    #[test]
+    fn test_repeat_exact() {
+        let field = re(r"\w+").save("field") + re(",").opt();
+        let lexer = Lexer::new(field.repeat(3, Some(3))).unwrap();
+        let tokens = lexer.lex("a,b,c").unwrap();
+        assert_eq!(
+            names(&tokens),
+            vec![("field", "a"), ("field", "b"), ("field", "c")]
+        );
+        assert!(lexer.lex("a,b").is_err());
+        assert_eq!(lexer.lex("a,b,c,d").unwrap_err().offset, 6);
+    }
+
+    #[test]
+    fn test_structure_mode_must_consume_input() {
+        let lexer = Lexer::new(re("a").save("a")).unwrap();
+        assert_eq!(names(&lexer.lex("a").unwrap()), vec![("a", "a")]);
+        let error = lexer.lex("abc").unwrap_err();
+        assert_eq!(error.offset, 1);
+        assert_eq!(error.message, "Unexpected input after the grammar");
+
+        let lexer = lexer.with_trivia(re(" +").save("ws"), Trivia::Attach);
+        let tokens = lexer.lex(" a  ").unwrap();
+        assert_eq!(names(&tokens[0].trailing), vec![("ws", "  ")]);
+        assert_eq!(lexer.lex(" a b").unwrap_err().offset, 3);
+    }
+
+    #[test]
+    fn test_repeat_range() {
+        let segment = re("/") + re(r"\w+").save("segment");
+        let lexer = Lexer::new(segment.repeat(2, Some(8)) + not(re("."))).unwrap();
+        assert!(lexer.lex("/a").is_err());
+        assert_eq!(lexer.lex("/a/b").unwrap().len(), 2);
+        assert_eq!(lexer.lex("/a/b/c/d/e/f/g/h").unwrap().len(), 8);
+        assert!(lexer.lex("/a/b/c/d/e/f/g/h/i").is_err());
+    }
+
+    #[test]
+    fn test_repeat_nested_and_unbounded() {
+        let pair = (re("a").save("a").repeat(2, Some(2)) + re(";")).repeat(1, None);
+        let lexer = Lexer::new(pair + not(re("."))).unwrap();
+        assert_eq!(lexer.lex("aa;aa;aa;").unwrap().len(), 6);
+        assert!(lexer.lex("aa;a;").is_err());
+        assert!(lexer.lex("").is_err());
+    }
+
+    #[test]
+    fn test_repeat_is_not_unrolled() {
+        let mut small = vec![];
+        let mut large = vec![];
+        pulp::lexer::vm::compile(&re("a").repeat(2, Some(3)), &mut small);
+        pulp::lexer::vm::compile(&re("a").repeat(200, Some(3000)), &mut large);
+        assert_eq!(small.len(), large.len());
+    }
+
+    #[test]
+    fn test_save_with_builtin_transforms() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                re(r"\d+\.\d+").save_with("float", &[Transform::Float]),
+                re(r"\d+").save_with("int", &[Transform::Int]),
+                re(r#""(\\.|\w|\s)*""#).save_with("string", &[Transform::Unescape]),
+                re("true|false").save_with("bool", &[Transform::Bool]),
+                re(r"\w+").save_with("word", &[Transform::Lowercase]),
+                re(r"\s+"),
+            ],
+            Select::First,
+        )
+        .unwrap();
+        let tokens = lexer.lex(r#"42 1.5 "a\"b\n" true MiXed"#).unwrap();
+        let values: Vec<_> = tokens.iter().map(|t| t.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Some(Value::Int(42)),
+                Some(Value::Float(1.5)),
+                Some(Value::Str("a\"b\n".to_string())),
+                Some(Value::Bool(true)),
+                Some(Value::Str("mixed".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_with_list_and_failed_conversion() {
+        let number = re(r"(\w|\d)+").save_with("n", &[Transform::Int]);
+        let list =
+            (number.clone() + (re(",") + number).many()).save_with("list", &[Transform::List]);
+        let lexer = Lexer::new(list).unwrap();
+        let tokens = lexer.lex("1,x,3").unwrap();
+        assert_eq!(
+            tokens[0].value,
+            Some(Value::List(vec![
+                Value::Int(1),
+                Value::Str("x".to_string()),
+                Value::Int(3)
+            ]))
+        );
+        assert_eq!(tokens[0].children[1].value, None);
+        assert_eq!(
+            lexer.lex("1").unwrap()[0].children[0].value,
+            Some(Value::Int(1))
+        );
+    }
+
+    #[test]
+    fn test_save_with_custom_hook() {
+        let hex = re(r"0x\w+").save_with("hex", &[Transform::Custom("hex".to_string())]);
+        let lexer = Lexer::new(hex.clone())
+            .unwrap()
+            .with_hook("hex", |value| match value {
+                Value::Str(s) => i64::from_str_radix(&s[2..], 16).ok().map(Value::Int),
+                _ => None,
+            });
+        assert_eq!(lexer.lex("0xff").unwrap()[0].value, Some(Value::Int(255)));
+        // without the hook the conversion does not apply
+        let lexer = Lexer::new(hex).unwrap();
+        assert_eq!(lexer.lex("0xff").unwrap()[0].value, None);
+    }
+
+    fn statements() -> Lexer {
+        Lexer::tokenizer(
+            vec![(re(r"\w+").save("name") + re(";")).save("stmt"), re(r"\s+")],
+            Select::First,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_without_recovery_stops_at_first_error() {
+        let (tokens, errors) = statements().lex_all("a; ?b; c;");
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].offset, 3);
+    }
+
+    #[test]
+    fn test_recovery_skip_char() {
+        let lexer = statements().with_recovery(Recovery::SkipChar);
+        let (tokens, errors) = lexer.lex_all("a; ?!b; c;");
+        assert_eq!(
+            names(&tokens),
+            vec![
+                ("stmt", "a;"),
+                ("error", "?"),
+                ("error", "!"),
+                ("stmt", "b;"),
+                ("stmt", "c;")
+            ]
+        );
+        let offsets: Vec<usize> = errors.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_recovery_sync() {
+        let lexer = statements().with_recovery(Recovery::Sync(vec![re(";")]));
+        let (tokens, errors) = lexer.lex_all("a; b c d; e;");
+        assert_eq!(
+            names(&tokens),
+            vec![("stmt", "a;"), ("error", "b c d;"), ("stmt", "e;")]
+        );
+        assert_eq!(errors.len(), 1);
+
+        let lexer = statements().with_recovery(Recovery::Sync(vec![and(re("let"))]));
+        let (tokens, _) = lexer.lex_all("a; ? ! let;");
+        assert_eq!(
+            names(&tokens),
+            vec![("stmt", "a;"), ("error", "? ! "), ("stmt", "let;")]
+        );
+    }
+
+    #[test]
+    fn test_recovery_insert_delimiters() {
+        let block = delimited(re(r"\{"), re(r"\w+").save("item"), re(r"\}")).save("block");
+        let lexer = Lexer::tokenizer(vec![block.clone(), re(r"\s+")], Select::First).unwrap();
+        assert!(lexer.lex("{a} {b").is_err());
+
+        let lexer = lexer.with_recovery(Recovery::InsertDelimiters);
+        let (tokens, errors) = lexer.lex_all("{a} {b");
+        assert_eq!(names(&tokens), vec![("block", "{a}"), ("block", "{b")]);
+        let missing = tokens[1].children.last().unwrap();
+        assert_eq!(missing.name, "missing");
+        assert_eq!((missing.start, missing.end), (6, 6));
+        assert_eq!(errors, vec![LexError::new(6, "Missing delimiter")]);
+    }
+
+    #[test]
+    fn test_recovery_in_structure_mode() {
+        let lexer = Lexer::new(re(r"\w+").save("word"))
+            .unwrap()
+            .with_recovery(Recovery::SkipChar);
+        let (tokens, errors) = lexer.lex_all("123");
+        assert_eq!(names(&tokens), vec![("error", "123")]);
+        assert_eq!(errors.len(), 1);
+
+        let (tokens, errors) = lexer.lex_all("abc?!");
+        assert_eq!(names(&tokens), vec![("word", "abc"), ("error", "?!")]);
+        assert_eq!(
+            errors,
+            vec![LexError::new(3, "Unexpected input after the grammar")]
+        );
+    }
+
+    #[test]
+    fn test_memo_keeps_output() {
+        // the alternatives read copies of `name` and each lookahead runs
+        // it again, at the same offsets
+        let name = re(r"\w+").save("name");
+        let rule = ordered(vec![
+            name.clone().then(re(r"\(")).then(re(r"\)")).save("call"),
+            name.clone().then(re("=")).then(re(r"\d+")).save("assign"),
+            name.when(not(re(r"\d"))).save("ref"),
+        ]);
+        let rules = vec![rule, re(";")];
+        let plain = Lexer::tokenizer(rules.clone(), Select::First)
+            .unwrap()
+            .with_trivia(re(" +"), Trivia::Attach);
+        for limit in [0, 2, 1000] {
+            let memo = plain.clone().with_memo(limit);
+            for input in ["f() ;x=1; y", "a;b=2 ;c()", "x= ;"] {
+                assert_eq!(memo.lex(input), plain.lex(input), "{:?}", input);
+            }
+        }
+    }
+
+    /// `rK := r(K-1) "x" / r(K-1) "y"` down to `r0 := "a"`, which matches
+    /// "a" followed by `n` "y"s by calling each rule twice per level.
+    fn nested_rules(n: usize) -> Lexer {
+        let mut lexer = Lexer::new(rule(&format!("r{}", n)))
+            .unwrap()
+            .with_rule("r0", re("a"));
+        for k in 1..=n {
+            let inner = rule(&format!("r{}", k - 1));
+            lexer = lexer.with_rule(
+                &format!("r{}", k),
+                ordered(vec![inner.clone() + re("x"), inner + re("y")]),
+            );
+        }
+        lexer
+    }
+
+    #[test]
+    fn test_memo_bounds_rule_calls() {
+        let steps = |n: usize, memo: bool| {
+            let lexer = match memo {
+                true => nested_rules(n).with_memo(1000),
+                false => nested_rules(n),
+            };
+            let input = format!("a{}", "y".repeat(n));
+            let (tokens, trace) = lexer.lex_traced(&input);
+            assert!(tokens.is_ok());
+            trace.steps.len()
+        };
+        // every level adds the same few steps with the memo, and doubles
+        // them without it
+        let (short, long) = (steps(8, true), steps(16, true));
+        assert!(long < 3 * short, "{} then {} steps", short, long);
+        assert!(steps(12, false) > 30 * steps(6, false));
+    }
+
+    #[test]
+    fn test_memo_with_longest() {
+        let rule = longest(vec![
+            re(r"\w+").save("word"),
+            re(r"\w+").save("name"),
+            keywords(&["if", "ifdef"]).save("keyword"),
+        ]);
+        let rule = ordered(vec![rule.clone().then(re("!")), rule]);
+        let plain = Lexer::tokenizer(vec![rule, re(" ")], Select::First).unwrap();
+        let memo = plain.clone().with_memo(100);
+        let input = "ifdef x! if";
+        assert_eq!(memo.lex(input), plain.lex(input));
+        assert_eq!(memo.lex_debug(input).1, plain.lex_debug(input).1);
+    }
+
+    /// Token names and texts as S-expressions, without spans.
+    fn tree(token: &Token) -> String {
+        let options = DumpOptions {
+            spans: false,
+            text: true,
+        };
+        token.to_sexpr(options)
+    }
+
+    #[test]
+    fn test_left_recursion() {
+        let term = re(r"\d+").save("num");
+        let lexer = Lexer::new(rule("expr")).unwrap().with_rule(
+            "expr",
+            ordered(vec![rule("expr").then(re(r"\+")).then(term.clone()), term]).save("expr"),
+        );
+        let tokens = lexer.lex("1+2+3").unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(
+            tree(&tokens[0]),
+            r#"(expr :text "1+2+3"
+  (expr :text "1+2"
+    (expr :text "1"
+      (num :text "1"
+        (_ :text "1")))
+    (_ :text "+")
+    (num :text "2"
+      (_ :text "2")))
+  (_ :text "+")
+  (num :text "3"
+    (_ :text "3")))"#
+        );
+        assert_eq!(lexer.lex("7").unwrap()[0].children[0].name, "num");
+        assert!(lexer.lex("+1").is_err());
+    }
+
+    #[test]
+    fn test_indirect_left_recursion() {
+        let lexer = Lexer::new(rule("a"))
+            .unwrap()
+            .with_rule(
+                "a",
+                ordered(vec![rule("b").then(re("x")), re("y")]).save("a"),
+            )
+            .with_rule("b", rule("a").then(re("z")).save("b"));
+        let tokens = lexer.lex("yzxzx").unwrap();
+        let mut texts = vec![];
+        let mut token = &tokens[0];
+        loop {
+            texts.push((token.name.as_str(), token.text));
+            match token.children.first() {
+                Some(child) if !child.name.is_empty() => token = child,
+                _ => break,
+            }
+        }
+        assert_eq!(
+            texts,
+            vec![
+                ("a", "yzxzx"),
+                ("b", "yzxz"),
+                ("a", "yzx"),
+                ("b", "yz"),
+                ("a", "y")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memo_with_left_recursion() {
+        let num = re(r"\d+").save("num");
+        let plain = Lexer::new(rule("expr"))
+            .unwrap()
+            .with_rule(
+                "expr",
+                ordered(vec![
+                    rule("expr").then(re(r"\+")).then(rule("term")),
+                    rule("expr").then(re("-")).then(rule("term")),
+                    rule("term"),
+                ])
+                .save("expr"),
+            )
+            .with_rule(
+                "term",
+                ordered(vec![rule("term").then(re(r"\*")).then(num.clone()), num]).save("term"),
+            );
+        // `b` only grows through the seed of `a`, so it must not be kept
+        let indirect = Lexer::new(rule("a"))
+            .unwrap()
+            .with_rule(
+                "a",
+                ordered(vec![rule("b").then(re("x")), re("y")]).save("a"),
+            )
+            .with_rule("b", rule("a").then(re("z")).save("b"));
+        let cases = [
+            (plain, vec!["1", "1+2*3-4", "1*2*3+4*5", "1+2-"]),
+            (indirect, vec!["y", "yzx", "yzxzx", "yzxz"]),
+        ];
+        for (plain, inputs) in cases {
+            for limit in [1, 5, 1000] {
+                let memo = plain.clone().with_memo(limit);
+                for input in &inputs {
+                    assert_eq!(memo.lex(input), plain.lex(input), "{:?}", input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rules_in_tokenizer() {
+        let digits = re(r"\d+");
+        let lexer = Lexer::tokenizer(
+            vec![rule("number").save("number"), rule("word"), re(" ")],
+            Select::First,
+        )
+        .unwrap()
+        .with_rule("number", digits.clone().then(re(r"\.").then(digits).opt()));
+        assert_eq!(
+            names(&lexer.lex("1.5 2").unwrap()),
+            vec![("number", "1.5"), ("number", "2")]
+        );
+        // `word` is never defined
+        assert!(lexer.lex("x").is_err());
+    }
+
+    /// The named tokens of a tree, with the text of its leaves.
+    fn shape(token: &Token) -> String {
+        let children: Vec<String> = token
+            .children
+            .iter()
+            .filter(|c| !c.name.is_empty())
+            .map(shape)
+            .collect();
+        match children.is_empty() {
+            true => token.text.to_string(),
+            false => format!("({} {})", token.name, children.join(" ")),
+        }
+    }
+
+    fn arithmetic() -> Lexer {
+        let operators = vec![
+            OpDef::new("add", re(r"\+"), Fixity::InfixLeft, 1),
+            OpDef::new("sub", re("-"), Fixity::InfixLeft, 1),
+            OpDef::new("mul", re(r"\*"), Fixity::InfixLeft, 2),
+            OpDef::new("neg", re("-"), Fixity::Prefix, 3),
+            OpDef::new("pow", re(r"\^"), Fixity::InfixRight, 4),
+            OpDef::new("fact", re("!"), Fixity::Postfix, 5),
+        ];
+        let atom = re(r"\d+").save("num");
+        Lexer::new(precedence(atom, operators).save("expr"))
+            .unwrap()
+            .with_trivia(re(" +"), Trivia::Drop)
+    }
+
+    #[test]
+    fn test_precedence() {
+        let lexer = arithmetic();
+        for (input, expected) in [
+            ("1", "(expr 1)"),
+            ("1 - 2 - 3", "(expr (sub (sub 1 2) 3))"),
+            ("1 + 2 * 3", "(expr (add 1 (mul 2 3)))"),
+            ("1 * 2 + 3", "(expr (add (mul 1 2) 3))"),
+            ("2 ^ 3 ^ 4", "(expr (pow 2 (pow 3 4)))"),
+            ("-2 ^ 2", "(expr (neg (pow 2 2)))"),
+            ("-1 - -2", "(expr (sub (neg 1) (neg 2)))"),
+            ("3! * 2", "(expr (mul (fact 3) 2))"),
+            ("2 * -3!", "(expr (mul 2 (neg (fact 3))))"),
+        ] {
+            let tokens = lexer.lex(input).unwrap();
+            assert_eq!(shape(&tokens[0]), expected, "{:?}", input);
+            assert_eq!(tokens[0].text, input);
+        }
+    }
+
+    #[test]
+    fn test_precedence_at_the_extremes() {
+        let operators = vec![
+            OpDef::new("min", re("-"), Fixity::InfixRight, 0),
+            OpDef::new("max", re(r"\+"), Fixity::InfixLeft, usize::MAX),
+        ];
+        let lexer = Lexer::new(precedence(re(r"\d+").save("num"), operators).save("expr")).unwrap();
+        let tokens = lexer.lex("1+2+3-4-5").unwrap();
+        assert_eq!(
+            shape(&tokens[0]),
+            "(expr (min (max (max 1 2) 3) (min 4 5)))"
+        );
+    }
+
+    #[test]
+    fn test_precedence_leaves_dangling_operator() {
+        let lexer = Lexer::tokenizer(
+            vec![
+                precedence(
+                    re(r"\w+").save("name"),
+                    vec![OpDef::new("dot", re(r"\."), Fixity::InfixLeft, 1)],
+                )
+                .save("path"),
+                re(r"\.").save("end"),
+            ],
+            Select::First,
+        )
+        .unwrap();
+        let tokens = lexer.lex("a.b.c.").unwrap();
+        assert_eq!(names(&tokens), vec![("path", "a.b.c"), ("end", ".")]);
+        assert_eq!(shape(&tokens[0]), "(path (dot (dot a b) c))");
+    }
+
+    fn indented() -> Lexer {
+        let rules = vec![
+            re(r"\w+").save("name"),
+            re(":").save("colon"),
+            re("\n").save("newline"),
+            re(" +"),
+        ];
+        Lexer::tokenizer(rules, Select::First)
+            .unwrap()
+            .with_indentation()
+    }
+
+    #[test]
+    fn test_indentation() {
+        let tokens = indented().lex("if a:\n  b\n\n  c\nd\n").unwrap();
+        let names: Vec<&str> = tokens.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "name", "name", "colon", "newline", "indent", "name", "newline", "newline", "name",
+                "newline", "dedent", "name", "newline"
+            ]
+        );
+        assert_eq!((tokens[4].start, tokens[4].end, tokens[4].text), (8, 8, ""));
+        assert_eq!((tokens[10].start, tokens[10].end), (15, 15));
+    }
+
+    #[test]
+    fn test_indentation_closed_at_end() {
+        let tokens = indented().lex("a:\n  b:\n    c").unwrap();
+        let virtual_tokens: Vec<(&str, usize)> = tokens
+            .iter()
+            .filter(|t| t.start == t.end)
+            .map(|t| (t.name.as_str(), t.start))
+            .collect();
+        assert_eq!(
+            virtual_tokens,
+            vec![
+                ("indent", 5),
+                ("indent", 12),
+                ("dedent", 13),
+                ("dedent", 13)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inconsistent_dedent() {
+        let input = "a\n    b\n  c\nd";
+        assert_eq!(
+            indented().lex(input),
+            Err(LexError::new(10, "Inconsistent dedent"))
+        );
+
+        let (tokens, errors) = indented().with_recovery(Recovery::SkipChar).lex_all(input);
+        assert_eq!(errors, vec![LexError::new(10, "Inconsistent dedent")]);
+        let levels: Vec<&str> = tokens
+            .iter()
+            .filter(|t| t.start == t.end)
+            .map(|t| t.name.as_str())
+            .collect();
+        assert_eq!(levels, vec!["indent", "dedent", "indent", "dedent"]);
+    }
+
+    #[test]
+    fn test_inconsistent_dedent_stays_balanced() {
+        let lexer = indented().with_recovery(Recovery::SkipChar);
+        for input in ["a\n    b\n  c\nd\n", "a\n    b\n  c\n   d\n e\n"] {
+            let (tokens, _) = lexer.lex_all(input);
+            let count = |name: &str| tokens.iter().filter(|t| t.name == name).count();
+            assert_eq!(count("indent"), count("dedent"), "{:?}", input);
+        }
+    }
+}
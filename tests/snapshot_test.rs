@@ -0,0 +1,39 @@
+#[cfg(test)]
+mod snapshot_tests {
+    use std::fs;
+
+    use pulp::lexer::builder::re;
+    use pulp::snapshot::{assert_snapshots, check_snapshots};
+    use pulp::{Lexer, Select, Transform};
+
+    fn assignments() -> Lexer {
+        let value = re(r"\d+").save_with("value", &[Transform::Int]);
+        let assign = (re(r"\w+").save("name") + re(" = ") + value).save("assign");
+        Lexer::tokenizer(vec![assign, re(r"\s+")], Select::First).unwrap()
+    }
+
+    #[test]
+    fn test_snapshots() {
+        assert_snapshots(&assignments(), "tests/snapshots/assign");
+    }
+
+    #[test]
+    fn test_bless_and_mismatch() {
+        let dir = std::env::temp_dir().join(format!("pulp-snapshots-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("one.txt"), "a = 1").unwrap();
+
+        let lexer = assignments();
+        let missing = check_snapshots(&lexer, &dir, false).unwrap_err();
+        assert!(missing[0].contains("missing"));
+
+        check_snapshots(&lexer, &dir, true).unwrap();
+        assert!(dir.join("one.txt.expected").is_file());
+        check_snapshots(&lexer, &dir, false).unwrap();
+
+        fs::write(dir.join("one.txt"), "a = 2").unwrap();
+        let differ = check_snapshots(&lexer, &dir, false).unwrap_err();
+        assert!(differ[0].contains("line 1"), "{:?}", differ);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
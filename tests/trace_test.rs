@@ -0,0 +1,101 @@
+#[cfg(test)]
+mod trace_tests {
+    use pulp::lexer::builder::{keywords, longest, re, rule};
+    use pulp::{Lexer, Select, Trivia};
+
+    fn lexer() -> Lexer {
+        let word = longest(vec![
+            re(r"\w+").save("name"),
+            keywords(&["if", "in"]).save("keyword"),
+        ]);
+        Lexer::tokenizer(vec![word, rule("end")], Select::First)
+            .unwrap()
+            .with_trivia(re(" +"), Trivia::Drop)
+            .with_rule("end", re(";").save("end"))
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(
+            lexer().disassemble(),
+            r"mode default:
+  rule 1:
+         0  longest 2
+      alternative 0:
+             0  start_capture name
+             1  match /\w+/
+             2  end_capture name
+             3  save name
+      alternative 1:
+             0  start_capture keyword
+             1  keywords if in
+             2  end_capture keyword
+             3  save keyword
+  rule 2:
+         0  call end
+trivia:
+       0  match / +/
+rule end:
+       0  start_capture end
+       1  match /;/
+       2  end_capture end
+       3  save end
+"
+        );
+    }
+
+    #[test]
+    fn test_lex_traced() {
+        let lexer = lexer();
+        let (tokens, trace) = lexer.lex_traced("if x;");
+        assert_eq!(tokens, lexer.lex("if x;"));
+
+        let steps: Vec<(usize, usize, usize, &str)> = trace
+            .steps
+            .iter()
+            .take(4)
+            .map(|s| (s.depth, s.pc, s.offset, s.instruction.as_str()))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (0, 0, 0, "longest 2"),
+                (1, 0, 0, "start_capture name"),
+                (1, 1, 0, r"match /\w+/"),
+                (1, 2, 2, "end_capture name"),
+            ]
+        );
+        assert_eq!(trace.steps[2].captures, vec!["name"]);
+        assert!(trace.steps.iter().all(|s| s.threads == 0));
+
+        let last = trace.steps.last().unwrap();
+        assert_eq!((last.depth, last.instruction.as_str()), (1, "save end"));
+        let text = trace.to_string();
+        assert_eq!(text.lines().count(), trace.steps.len() + 1);
+        assert!(text.contains(r"match /\w+/  [name]"));
+    }
+
+    #[test]
+    fn test_threads_are_counted() {
+        let lexer = Lexer::new((re("a") | re("ab")).save("head").then(re("c"))).unwrap();
+        let (tokens, trace) = lexer.lex_traced("abc");
+        assert!(tokens.is_ok());
+        let split = trace
+            .steps
+            .iter()
+            .position(|s| s.instruction.starts_with("split"))
+            .unwrap();
+        assert_eq!(trace.steps[split].threads, 0);
+        assert_eq!(trace.steps[split + 1].threads, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_trace_serializes_as_steps() {
+        let (_, trace) = lexer().lex_traced(";");
+        let json = serde_json::to_value(&trace).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), trace.steps.len());
+        assert_eq!(json[0]["instruction"], "longest 2");
+        assert_eq!(json[0]["captures"], serde_json::json!([]));
+    }
+}